@@ -0,0 +1,913 @@
+// Assembler for an Octo-style CHIP-8 dialect, with SUPER-CHIP and XO-CHIP extensions.
+//
+// Supported directives are `: label`, `:const name value`, `:alias name vX`,
+// `:byte value`, `:org address`, `:call address`, `:include "file"` and
+// `:macro name args { body }`. Bare numbers are emitted as data bytes and bare
+// label names assemble to a call of that label.
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::instruction::Instruction;
+
+// Programs are assembled to run from the usual CHIP-8 entry point
+pub const ORIGIN: u16 = 0x200;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    file: Rc<PathBuf>,
+    line: usize,
+    column: usize,
+    // How many includes deep the file of the token is, 0 for the main file
+    include_depth: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            file: self.file.display().to_string(),
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+// Split a source file into whitespace-separated tokens, dropping `#` comments
+fn tokenize(source: &str, file: Rc<PathBuf>) -> Result<Vec<Token>, AsmError> {
+    let mut tokens: Vec<Token> = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let mut chars = line.char_indices().peekable();
+
+        while let Some((start, c)) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+
+            if c == '#' {
+                break;
+            }
+
+            let mut end = start + c.len_utf8();
+
+            if c == '"' {
+                // Strings keep their spaces, they are only used for :include paths
+                let mut closed = false;
+                for (index, c) in chars.by_ref() {
+                    end = index + c.len_utf8();
+                    if c == '"' {
+                        closed = true;
+                        break;
+                    }
+                }
+
+                if !closed {
+                    return Err(AsmError {
+                        file: file.display().to_string(),
+                        line: line_index + 1,
+                        column: line[..start].chars().count() + 1,
+                        message: String::from("Unterminated string"),
+                    });
+                }
+            } else {
+                while let Some((index, c)) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+            }
+
+            tokens.push(Token {
+                text: line[start..end].to_string(),
+                file: Rc::clone(&file),
+                line: line_index + 1,
+                column: line[..start].chars().count() + 1,
+                include_depth: 0,
+            });
+        }
+    }
+
+    return Ok(tokens);
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Locations that need the address of a label once every label is known
+struct Fixup {
+    address: u16,
+    label: String,
+    token: Token,
+    long: bool,
+}
+
+enum Control {
+    // Address of the jump over the body of an if ... begin block
+    If(u16, Token),
+    Else(u16, Token),
+    // Loop start address, and the jumps out of the loop made by `while`
+    Loop(u16, Vec<u16>, Token),
+}
+
+enum Value {
+    Number(i32),
+    Label(String),
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    output: Vec<u8>,
+    address: u16,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i32>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    control: Vec<Control>,
+    // Last token read, used to locate errors at the end of the input
+    last: Option<Token>,
+}
+
+const MAX_INCLUDE_DEPTH: usize = 32;
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i32::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse::<i32>().ok()?
+    };
+
+    return Some(if negative { -value } else { value });
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+
+    return chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+}
+
+impl Assembler {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self {
+            tokens: tokens.into(),
+            output: Vec::new(),
+            address: ORIGIN,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            control: Vec::new(),
+            last: None,
+        }
+    }
+
+    fn end_error(&self, message: &str) -> AsmError {
+        match &self.last {
+            Some(token) => token.error(message.to_string()),
+            None => AsmError {
+                file: String::new(),
+                line: 0,
+                column: 0,
+                message: message.to_string(),
+            },
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = Some(token.clone());
+                Ok(token)
+            }
+            None => Err(self.end_error("Unexpected end of input")),
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|token| token.text == text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+
+        if token.text != text {
+            return Err(token.error(format!("Expected '{}', found '{}'", text, token.text)));
+        }
+
+        return Ok(token);
+    }
+
+    fn emit_byte(&mut self, byte: u8, token: &Token) -> Result<(), AsmError> {
+        if self.address < ORIGIN {
+            return Err(token.error(String::from("Program grew past the end of memory")));
+        }
+
+        let index = (self.address - ORIGIN) as usize;
+        if index >= self.output.len() {
+            self.output.resize(index + 1, 0);
+        }
+        self.output[index] = byte;
+        self.address = self.address.wrapping_add(1);
+
+        return Ok(());
+    }
+
+    fn emit_word(&mut self, word: u16, token: &Token) -> Result<(), AsmError> {
+        self.emit_byte((word >> 8) as u8, token)?;
+        self.emit_byte(word as u8, token)
+    }
+
+    fn emit(&mut self, instruction: Instruction, token: &Token) -> Result<(), AsmError> {
+        self.emit_word(instruction.encode(), token)
+    }
+
+    // Emit an instruction whose 12-bit address operand may be a label defined later
+    fn emit_with_address(&mut self, build: fn(u16) -> Instruction, token: &Token) -> Result<(), AsmError> {
+        let operand = self.next()?;
+        let at = self.address;
+
+        match self.value(&operand)? {
+            Value::Number(address) => {
+                if !(0..=0xFFF).contains(&address) {
+                    return Err(operand.error(format!("Address {:#X} does not fit in 12 bits", address)));
+                }
+                self.emit(build(address as u16), token)
+            }
+            Value::Label(label) => {
+                self.fixups.push(Fixup { address: at, label, token: operand, long: false });
+                self.emit(build(0), token)
+            }
+        }
+    }
+
+    fn value(&self, token: &Token) -> Result<Value, AsmError> {
+        if let Some(number) = parse_number(&token.text) {
+            return Ok(Value::Number(number));
+        }
+
+        if let Some(constant) = self.constants.get(&token.text) {
+            return Ok(Value::Number(*constant));
+        }
+
+        if is_identifier(&token.text) && self.register_of(&token.text).is_none() {
+            return Ok(Value::Label(token.text.clone()));
+        }
+
+        return Err(token.error(format!("Expected a value, found '{}'", token.text)));
+    }
+
+    // A numeric value known right away, labels are only allowed once defined
+    fn number(&mut self, min: i32, max: i32) -> Result<i32, AsmError> {
+        let token = self.next()?;
+
+        let number = match self.value(&token)? {
+            Value::Number(number) => number,
+            Value::Label(label) => match self.labels.get(&label) {
+                Some(address) => *address as i32,
+                None => return Err(token.error(format!("Unknown constant '{}'", label))),
+            },
+        };
+
+        if number < min || number > max {
+            return Err(token.error(format!("Value {} is out of range {}..{}", number, min, max)));
+        }
+
+        return Ok(number);
+    }
+
+    // Bytes accept both signed and unsigned values
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        Ok(self.number(-128, 255)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        Ok(self.number(0, 15)? as u8)
+    }
+
+    fn register_of(&self, text: &str) -> Option<u8> {
+        if let Some(register) = self.aliases.get(text) {
+            return Some(*register);
+        }
+
+        let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+        if digit.len() != 1 {
+            return None;
+        }
+
+        return u8::from_str_radix(digit, 16).ok();
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+
+        match self.register_of(&token.text) {
+            Some(register) => Ok(register),
+            None => Err(token.error(format!("Expected a register, found '{}'", token.text))),
+        }
+    }
+
+    // Overwrite the 12-bit operand of the jump at `at` with the current address
+    fn patch_jump(&mut self, at: u16, token: &Token) -> Result<(), AsmError> {
+        let index = (at - ORIGIN) as usize;
+        let target = self.address;
+
+        if target > 0xFFF {
+            return Err(token.error(format!("Jump target {:#X} is beyond 0xFFF", target)));
+        }
+
+        self.output[index] = (self.output[index] & 0xF0) | (target >> 8) as u8;
+        self.output[index + 1] = target as u8;
+
+        return Ok(());
+    }
+
+    fn assemble(&mut self) -> Result<(), AsmError> {
+        let mut expansions = 0;
+
+        while let Some(token) = self.tokens.pop_front() {
+            self.last = Some(token.clone());
+
+            if let Some(body) = self.expand_macro(&token)? {
+                expansions += 1;
+                if expansions > MAX_MACRO_EXPANSIONS {
+                    return Err(token.error(String::from("Too many macro expansions, is a macro recursive?")));
+                }
+
+                for body_token in body.into_iter().rev() {
+                    self.tokens.push_front(body_token);
+                }
+                continue;
+            }
+
+            self.statement(token)?;
+        }
+
+        if let Some(control) = self.control.last() {
+            let (token, name) = match control {
+                Control::If(_, token) | Control::Else(_, token) => (token, "begin"),
+                Control::Loop(_, _, token) => (token, "loop"),
+            };
+            return Err(token.error(format!("Unclosed '{}'", name)));
+        }
+
+        for fixup in self.fixups.iter() {
+            let address = match self.labels.get(&fixup.label) {
+                Some(address) => *address,
+                None => return Err(fixup.token.error(format!("Undefined label '{}'", fixup.label))),
+            };
+
+            let index = (fixup.address - ORIGIN) as usize;
+            if fixup.long {
+                self.output[index] = (address >> 8) as u8;
+                self.output[index + 1] = address as u8;
+            } else {
+                if address > 0xFFF {
+                    return Err(fixup.token.error(format!("Label '{}' is beyond 0xFFF, use 'i := long'", fixup.label)));
+                }
+                self.output[index] |= (address >> 8) as u8;
+                self.output[index + 1] = address as u8;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn expand_macro(&mut self, token: &Token) -> Result<Option<Vec<Token>>, AsmError> {
+        let parameter_count = match self.macros.get(&token.text) {
+            Some(definition) => definition.parameters.len(),
+            None => return Ok(None),
+        };
+
+        let mut arguments: HashMap<String, String> = HashMap::new();
+        for index in 0..parameter_count {
+            let argument = self.next()?;
+            let name = self.macros[&token.text].parameters[index].clone();
+            arguments.insert(name, argument.text);
+        }
+
+        let body = self.macros[&token.text]
+            .body
+            .iter()
+            .map(|body_token| Token {
+                text: arguments.get(&body_token.text).cloned().unwrap_or_else(|| body_token.text.clone()),
+                ..body_token.clone()
+            })
+            .collect();
+
+        return Ok(Some(body));
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.next()?;
+        if !is_identifier(&name.text) {
+            return Err(name.error(format!("Invalid macro name '{}'", name.text)));
+        }
+
+        let mut parameters: Vec<String> = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            parameters.push(token.text);
+        }
+
+        let mut body: Vec<Token> = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name.text, Macro { parameters, body });
+
+        return Ok(());
+    }
+
+    fn include(&mut self, directive: &Token) -> Result<(), AsmError> {
+        let path_token = self.next()?;
+        let relative = path_token.text.trim_matches('"');
+
+        // Siblings share a depth, only files included from included files go deeper
+        if directive.include_depth >= MAX_INCLUDE_DEPTH {
+            return Err(path_token.error(String::from("Includes are nested too deeply")));
+        }
+
+        // Included paths are relative to the including file
+        let base = directive.file.parent().unwrap_or_else(|| Path::new(""));
+        let path = base.join(relative);
+        let source = fs::read_to_string(&path)
+            .map_err(|error| path_token.error(format!("Could not include '{}': {}", path.display(), error)))?;
+
+        let tokens = tokenize(&source, Rc::new(path))?;
+        for token in tokens.into_iter().rev() {
+            self.tokens.push_front(Token { include_depth: directive.include_depth + 1, ..token });
+        }
+
+        return Ok(());
+    }
+
+    // Instructions skipping the next one when the condition is (true, false)
+    fn condition(&mut self) -> Result<(Instruction, Instruction), AsmError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+
+        match operator.text.as_str() {
+            "key" => return Ok((Instruction::Skp(x), Instruction::Sknp(x))),
+            "-key" => return Ok((Instruction::Sknp(x), Instruction::Skp(x))),
+            "==" | "!=" => {}
+            _ => return Err(operator.error(format!("Unsupported condition '{}'", operator.text))),
+        }
+
+        let rhs = self.tokens.front().cloned().ok_or_else(|| self.end_error("Unexpected end of input"))?;
+        let equal = match self.register_of(&rhs.text) {
+            Some(y) => {
+                self.next()?;
+                (Instruction::SeReg(x, y), Instruction::SneReg(x, y))
+            }
+            None => {
+                let nn = self.byte()?;
+                (Instruction::SeImm(x, nn), Instruction::SneImm(x, nn))
+            }
+        };
+
+        return Ok(if operator.text == "==" { equal } else { (equal.1, equal.0) });
+    }
+
+    fn register_statement(&mut self, x: u8, token: &Token) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        let rhs = self.tokens.front().cloned().ok_or_else(|| self.end_error("Unexpected end of input"))?;
+        let y = self.register_of(&rhs.text);
+
+        let instruction = match (operator.text.as_str(), y) {
+            (":=", Some(y)) => Instruction::LdReg(x, y),
+            (":=", None) => match rhs.text.as_str() {
+                "random" => {
+                    self.next()?;
+                    Instruction::Rnd(x, self.byte()?)
+                }
+                "delay" => Instruction::LdVxDt(x),
+                "key" => Instruction::LdVxK(x),
+                _ => Instruction::LdImm(x, self.byte()?),
+            },
+            ("+=", Some(y)) => Instruction::AddReg(x, y),
+            ("+=", None) => Instruction::AddImm(x, self.byte()?),
+            ("-=", Some(y)) => Instruction::Sub(x, y),
+            ("-=", None) => Instruction::AddImm(x, self.byte()?.wrapping_neg()),
+            ("=-", Some(y)) => Instruction::Subn(x, y),
+            ("|=", Some(y)) => Instruction::Or(x, y),
+            ("&=", Some(y)) => Instruction::And(x, y),
+            ("^=", Some(y)) => Instruction::Xor(x, y),
+            (">>=", Some(y)) => Instruction::Shr(x, y),
+            ("<<=", Some(y)) => Instruction::Shl(x, y),
+            _ => return Err(operator.error(format!("Unsupported operation '{} {}'", operator.text, rhs.text))),
+        };
+
+        // Operands that were only peeked at still have to be consumed
+        if y.is_some() || rhs.text == "delay" || rhs.text == "key" {
+            self.next()?;
+        }
+
+        return self.emit(instruction, token);
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                if !is_identifier(&name.text) {
+                    return Err(name.error(format!("Invalid label name '{}'", name.text)));
+                }
+                if self.labels.insert(name.text.clone(), self.address).is_some() {
+                    return Err(name.error(format!("Label '{}' is already defined", name.text)));
+                }
+            }
+            ":const" => {
+                let name = self.next()?;
+                if !is_identifier(&name.text) {
+                    return Err(name.error(format!("Invalid constant name '{}'", name.text)));
+                }
+                let value = self.number(i32::MIN, i32::MAX)?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                if !is_identifier(&name.text) {
+                    return Err(name.error(format!("Invalid alias name '{}'", name.text)));
+                }
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(byte, &token)?;
+            }
+            ":org" => {
+                let address = self.number(ORIGIN as i32, 0xFFFF)?;
+                self.address = address as u16;
+            }
+            ":macro" => self.define_macro()?,
+            ":include" => self.include(&token)?,
+            ":call" => self.emit_with_address(Instruction::Call, &token)?,
+            "clear" => self.emit(Instruction::Cls, &token)?,
+            "return" | ";" => self.emit(Instruction::Ret, &token)?,
+            "exit" => self.emit(Instruction::Exit, &token)?,
+            "lores" => self.emit(Instruction::Low, &token)?,
+            "hires" => self.emit(Instruction::High, &token)?,
+            "scroll-right" => self.emit(Instruction::ScrollRight, &token)?,
+            "scroll-left" => self.emit(Instruction::ScrollLeft, &token)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollDown(n), &token)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(Instruction::ScrollUp(n), &token)?;
+            }
+            "audio" => self.emit(Instruction::Audio, &token)?,
+            "jump" => self.emit_with_address(Instruction::Jp, &token)?,
+            "jump0" => self.emit_with_address(Instruction::JpV0, &token)?,
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdB(x), &token)?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let instruction = if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    if token.text == "save" { Instruction::SaveRange(x, y) } else { Instruction::LoadRange(x, y) }
+                } else if token.text == "save" {
+                    Instruction::LdIVx(x)
+                } else {
+                    Instruction::LdVxI(x)
+                };
+                self.emit(instruction, &token)?;
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdRVx(x), &token)?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(Instruction::LdVxR(x), &token)?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(Instruction::Drw(x, y, n), &token)?;
+            }
+            "plane" => {
+                let n = self.number(0, 3)? as u8;
+                self.emit(Instruction::Plane(n), &token)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let instruction = match token.text.as_str() {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::Pitch(x),
+                };
+                self.emit(instruction, &token)?;
+            }
+            "i" => {
+                let operator = self.next()?;
+                match operator.text.as_str() {
+                    "+=" => {
+                        let x = self.register()?;
+                        self.emit(Instruction::AddI(x), &token)?;
+                    }
+                    ":=" if self.peek_is("hex") => {
+                        self.next()?;
+                        let x = self.register()?;
+                        self.emit(Instruction::LdF(x), &token)?;
+                    }
+                    ":=" if self.peek_is("bighex") => {
+                        self.next()?;
+                        let x = self.register()?;
+                        self.emit(Instruction::LdHf(x), &token)?;
+                    }
+                    ":=" if self.peek_is("long") => {
+                        self.next()?;
+                        self.emit(Instruction::LdILong, &token)?;
+                        let operand = self.next()?;
+                        let at = self.address;
+                        match self.value(&operand)? {
+                            Value::Number(address) => {
+                                if !(0..=0xFFFF).contains(&address) {
+                                    return Err(operand.error(format!("Address {:#X} does not fit in 16 bits", address)));
+                                }
+                                self.emit_word(address as u16, &token)?;
+                            }
+                            Value::Label(label) => {
+                                self.fixups.push(Fixup { address: at, label, token: operand, long: true });
+                                self.emit_word(0, &token)?;
+                            }
+                        }
+                    }
+                    ":=" => self.emit_with_address(Instruction::LdI, &token)?,
+                    _ => return Err(operator.error(format!("Unsupported operation 'i {}'", operator.text))),
+                }
+            }
+            "if" => {
+                let (skip_if_true, skip_if_false) = self.condition()?;
+                let keyword = self.next()?;
+                match keyword.text.as_str() {
+                    "then" => self.emit(skip_if_false, &token)?,
+                    "begin" => {
+                        // Skip the jump over the body when the condition holds
+                        self.emit(skip_if_true, &token)?;
+                        let at = self.address;
+                        self.emit(Instruction::Jp(0), &token)?;
+                        self.control.push(Control::If(at, token));
+                    }
+                    _ => return Err(keyword.error(format!("Expected 'then' or 'begin', found '{}'", keyword.text))),
+                }
+            }
+            "else" => match self.control.pop() {
+                Some(Control::If(at, _)) => {
+                    let else_at = self.address;
+                    self.emit(Instruction::Jp(0), &token)?;
+                    self.patch_jump(at, &token)?;
+                    self.control.push(Control::Else(else_at, token));
+                }
+                _ => return Err(token.error(String::from("'else' without 'if ... begin'"))),
+            },
+            "end" => match self.control.pop() {
+                Some(Control::If(at, _)) | Some(Control::Else(at, _)) => self.patch_jump(at, &token)?,
+                _ => return Err(token.error(String::from("'end' without 'if ... begin'"))),
+            },
+            "loop" => self.control.push(Control::Loop(self.address, Vec::new(), token)),
+            "while" => {
+                let (skip_if_true, _) = self.condition()?;
+                self.emit(skip_if_true, &token)?;
+                let at = self.address;
+                self.emit(Instruction::Jp(0), &token)?;
+
+                match self.control.iter_mut().rev().find(|control| matches!(control, Control::Loop(..))) {
+                    Some(Control::Loop(_, breaks, _)) => breaks.push(at),
+                    _ => return Err(token.error(String::from("'while' outside of a loop"))),
+                }
+            }
+            "again" => match self.control.pop() {
+                Some(Control::Loop(start, breaks, _)) => {
+                    if start > 0xFFF {
+                        return Err(token.error(format!("Jump target {:#X} is beyond 0xFFF", start)));
+                    }
+                    self.emit(Instruction::Jp(start), &token)?;
+                    for at in breaks {
+                        self.patch_jump(at, &token)?;
+                    }
+                }
+                _ => return Err(token.error(String::from("'again' without 'loop'"))),
+            },
+            text => {
+                if let Some(x) = self.register_of(text) {
+                    return self.register_statement(x, &token);
+                }
+
+                match self.value(&token)? {
+                    Value::Number(number) => {
+                        if !(-128..=255).contains(&number) {
+                            return Err(token.error(format!("Value {} does not fit in a byte", number)));
+                        }
+                        self.emit_byte(number as u8, &token)?;
+                    }
+                    // A bare label name calls it
+                    Value::Label(label) => {
+                        let at = self.address;
+                        self.fixups.push(Fixup { address: at, label, token: token.clone(), long: false });
+                        self.emit(Instruction::Call(0), &token)?;
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+// Assemble source text, `file` is used for error messages and to resolve includes
+pub fn assemble(source: &str, file: &Path) -> Result<Vec<u8>, AsmError> {
+    let tokens = tokenize(source, Rc::new(file.to_path_buf()))?;
+    let mut assembler = Assembler::new(tokens);
+
+    assembler.assemble()?;
+
+    return Ok(assembler.output);
+}
+
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let source = fs::read_to_string(path).map_err(|error| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        column: 0,
+        message: format!("Could not read file: {}", error),
+    })?;
+
+    return assemble(&source, path);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn assemble_text(source: &str) -> Result<Vec<u8>, AsmError> {
+        return assemble(source, Path::new("test.8o"));
+    }
+
+    // A fresh directory for the files of an include test
+    fn include_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("chipr-asm-{}-{}", name, process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        return directory;
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_defined() {
+        let rom = assemble_text(": main jump end v0 := 1 : end clear").unwrap();
+        assert_eq!(rom, [0x12, 0x04, 0x60, 0x01, 0x00, 0xE0]);
+
+        // Bare label names call the label
+        let rom = assemble_text(": main sub exit : sub return").unwrap();
+        assert_eq!(rom, [0x22, 0x04, 0x00, 0xFD, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn undefined_labels_are_reported_where_they_are_used() {
+        let error = assemble_text(": main\n  clear\n  jump nowhere").unwrap_err();
+
+        assert_eq!(error.message, "Undefined label 'nowhere'");
+        assert_eq!((error.file.as_str(), error.line, error.column), ("test.8o", 3, 8));
+    }
+
+    #[test]
+    fn macros_substitute_their_arguments() {
+        let rom = assemble_text(":macro set-pair a b { v0 := a v1 := b }\nset-pair 1 2 set-pair 3 4").unwrap();
+        assert_eq!(rom, [0x60, 0x01, 0x61, 0x02, 0x60, 0x03, 0x61, 0x04]);
+    }
+
+    #[test]
+    fn errors_point_at_the_line_and_column_of_the_token() {
+        let error = assemble_text("clear\n\n   v0 += vz").unwrap_err();
+        assert_eq!((error.line, error.column), (3, 10));
+
+        let error = assemble_text("clear\n  :include \"missing").unwrap_err();
+        assert_eq!(error.message, "Unterminated string");
+        assert_eq!((error.line, error.column), (2, 12));
+    }
+
+    #[test]
+    fn if_else_end_jumps_over_each_branch() {
+        let rom = assemble_text("if v0 == 1 begin v1 := 2 else v1 := 3 end clear").unwrap();
+
+        // SE v0, 1 skips the jump into the else branch, which starts at 0x208
+        assert_eq!(rom, [0x30, 0x01, 0x12, 0x08, 0x61, 0x02, 0x12, 0x0A, 0x61, 0x03, 0x00, 0xE0]);
+
+        let rom = assemble_text("if v0 != v1 begin clear end").unwrap();
+        assert_eq!(rom, [0x90, 0x10, 0x12, 0x06, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn while_jumps_out_of_the_loop_and_again_jumps_back() {
+        let rom = assemble_text("loop v0 += 1 while v0 != 5 v1 += 1 again clear").unwrap();
+
+        assert_eq!(rom, [0x70, 0x01, 0x40, 0x05, 0x12, 0x0A, 0x71, 0x01, 0x12, 0x00, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn control_flow_past_0xfff_is_an_error() {
+        let error = assemble_text(":org 0xFFC
+if v0 == 1 begin
+  clear
+end").unwrap_err();
+        assert_eq!(error.message, "Jump target 0x1002 is beyond 0xFFF");
+        assert_eq!((error.line, error.column), (4, 1));
+
+        let error = assemble_text(":org 0x1000
+loop
+  clear
+again").unwrap_err();
+        assert_eq!(error.message, "Jump target 0x1000 is beyond 0xFFF");
+        assert_eq!((error.line, error.column), (4, 1));
+    }
+
+    #[test]
+    fn sibling_includes_do_not_count_towards_the_depth() {
+        let directory = include_directory("siblings");
+        fs::write(directory.join("part.8o"), "v0 := 1").unwrap();
+
+        let source = ":include \"part.8o\"\n".repeat(MAX_INCLUDE_DEPTH + 8);
+        let rom = assemble(&source, &directory.join("main.8o")).unwrap();
+
+        assert_eq!(rom.len(), 2 * (MAX_INCLUDE_DEPTH + 8));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn recursive_includes_stop_at_the_maximum_depth() {
+        let directory = include_directory("recursive");
+        fs::write(directory.join("self.8o"), ":include \"self.8o\"").unwrap();
+
+        let error = assemble_file(&directory.join("self.8o")).unwrap_err();
+
+        assert_eq!(error.message, "Includes are nested too deeply");
+        assert_eq!((error.line, error.column), (1, 10));
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
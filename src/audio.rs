@@ -0,0 +1,264 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::io;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Number of samples played during one 60 Hz timer tick
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / 60) as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name.to_lowercase().as_str() {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+
+    // Value of the waveform in [-1, 1] at the given phase, in cycles
+    fn sample(&self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase.fract() < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioSettings {
+    pub waveform: Waveform,
+    pub frequency: f32,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+            muted: false,
+        }
+    }
+}
+
+// Generate `count` samples of the buzzer tone, starting at the given phase
+fn generate_samples(settings: &AudioSettings, phase: f32, count: usize) -> Vec<i16> {
+    let step = settings.frequency / SAMPLE_RATE as f32;
+
+    (0..count)
+        .map(|n| {
+            let value = settings.waveform.sample(phase + step * n as f32) * settings.volume;
+            (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+        })
+        .collect()
+}
+
+// One second of tone at full volume, which loops without clicks for whole frequencies
+pub fn generate_tone(settings: &AudioSettings) -> Vec<i16> {
+    let full_volume = AudioSettings {
+        volume: 1.0,
+        ..*settings
+    };
+
+    return generate_samples(&full_volume, 0.0, SAMPLE_RATE as usize);
+}
+
+// Encode 16-bit mono samples as a PCM WAV file
+pub fn encode_wav(samples: &[i16]) -> Vec<u8> {
+    let data_length = (samples.len() * 2) as u32;
+    let mut wav: Vec<u8> = Vec::with_capacity(44 + data_length as usize);
+
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_length).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // Byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // Block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_length.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    return wav;
+}
+
+// Records the buzzer output frame by frame, for runs without a sound card
+pub struct BuzzerRecorder {
+    pub settings: AudioSettings,
+    pub samples: Vec<i16>,
+    phase: f32,
+}
+
+impl BuzzerRecorder {
+    pub fn new(settings: AudioSettings) -> Self {
+        Self {
+            settings,
+            samples: Vec::new(),
+            phase: 0.0,
+        }
+    }
+
+    // Append one 60 Hz frame of tone or silence
+    pub fn record_frame(&mut self, sound_on: bool) {
+        if sound_on && !self.settings.muted {
+            let frame = generate_samples(&self.settings, self.phase, SAMPLES_PER_FRAME);
+            self.samples.extend(frame);
+        } else {
+            self.samples.resize(self.samples.len() + SAMPLES_PER_FRAME, 0);
+        }
+
+        // Keep the phase continuous so consecutive frames don't click
+        self.phase = (self.phase + self.settings.frequency * SAMPLES_PER_FRAME as f32 / SAMPLE_RATE as f32).fract();
+    }
+
+    pub fn write_wav(&self, path: &str) -> io::Result<()> {
+        fs::write(path, encode_wav(&self.samples))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(wav: &[u8], offset: usize) -> u16 {
+        return u16::from_le_bytes([wav[offset], wav[offset + 1]]);
+    }
+
+    fn read_u32(wav: &[u8], offset: usize) -> u32 {
+        return u32::from_le_bytes([wav[offset], wav[offset + 1], wav[offset + 2], wav[offset + 3]]);
+    }
+
+    // Number of times the signal goes from negative to positive
+    fn rising_edges(samples: &[i16]) -> usize {
+        return samples.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count();
+    }
+
+    #[test]
+    fn wav_header_describes_16_bit_mono_pcm() {
+        let wav = encode_wav(&[1, -2, 0x1234]);
+
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(read_u32(&wav, 4), 36 + 6);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(read_u32(&wav, 16), 16);
+        assert_eq!(read_u16(&wav, 20), 1);
+        assert_eq!(read_u16(&wav, 22), 1);
+        assert_eq!(read_u32(&wav, 24), SAMPLE_RATE);
+        assert_eq!(read_u32(&wav, 28), SAMPLE_RATE * 2);
+        assert_eq!(read_u16(&wav, 32), 2);
+        assert_eq!(read_u16(&wav, 34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(read_u32(&wav, 40), 6);
+        assert_eq!(&wav[44..], [0x01, 0x00, 0xFE, 0xFF, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn empty_wav_is_only_a_header() {
+        let wav = encode_wav(&[]);
+
+        assert_eq!(wav.len(), 44);
+        assert_eq!(read_u32(&wav, 4), 36);
+        assert_eq!(read_u32(&wav, 40), 0);
+    }
+
+    #[test]
+    fn recorder_writes_tone_only_while_the_buzzer_is_on() {
+        let mut recorder = BuzzerRecorder::new(AudioSettings::default());
+        recorder.record_frame(true);
+        recorder.record_frame(false);
+        recorder.record_frame(true);
+
+        let frames: Vec<&[i16]> = recorder.samples.chunks(SAMPLES_PER_FRAME).collect();
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].iter().any(|&sample| sample != 0));
+        assert!(frames[1].iter().all(|&sample| sample == 0));
+        assert!(frames[2].iter().any(|&sample| sample != 0));
+
+        // Volume 0.25 of full scale
+        let peak = frames[0].iter().map(|sample| sample.abs()).max().unwrap();
+        assert_eq!(peak, (0.25 * i16::MAX as f32) as i16);
+    }
+
+    #[test]
+    fn muted_recorder_writes_silence() {
+        let mut recorder = BuzzerRecorder::new(AudioSettings {
+            muted: true,
+            ..AudioSettings::default()
+        });
+        recorder.record_frame(true);
+        recorder.record_frame(true);
+
+        assert_eq!(recorder.samples.len(), 2 * SAMPLES_PER_FRAME);
+        assert!(recorder.samples.iter().all(|&sample| sample == 0));
+    }
+
+    #[test]
+    fn recorded_tone_keeps_its_frequency_across_frames() {
+        let mut recorder = BuzzerRecorder::new(AudioSettings {
+            frequency: 600.0,
+            ..AudioSettings::default()
+        });
+        for _ in 0..60 {
+            recorder.record_frame(true);
+        }
+
+        assert_eq!(recorder.samples.len(), SAMPLE_RATE as usize);
+        assert!((rising_edges(&recorder.samples) as i32 - 600).abs() <= 1);
+    }
+
+    #[test]
+    fn square_tone_has_the_requested_frequency() {
+        // 441 Hz is exactly 100 samples per period
+        let tone = generate_tone(&AudioSettings {
+            waveform: Waveform::Square,
+            frequency: 441.0,
+            volume: 0.1,
+            muted: false,
+        });
+
+        assert_eq!(tone.len(), SAMPLE_RATE as usize);
+        assert_eq!(rising_edges(&tone), 440);
+        assert!(tone[..50].iter().all(|&sample| sample == i16::MAX));
+        assert!(tone[50..100].iter().all(|&sample| sample == -i16::MAX));
+    }
+
+    #[test]
+    fn sine_tone_has_the_requested_frequency() {
+        let tone = generate_tone(&AudioSettings {
+            waveform: Waveform::Sine,
+            frequency: 440.0,
+            ..AudioSettings::default()
+        });
+
+        assert_eq!(tone.len(), SAMPLE_RATE as usize);
+        assert!((rising_edges(&tone) as i32 - 440).abs() <= 1);
+        assert_eq!(tone[0], 0);
+        // The tone is generated at full volume
+        let peak = tone.iter().max().unwrap();
+        assert!(*peak > i16::MAX - 10);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::chip8::VM;
+use crate::chip8::{StepResult, VM};
 
 pub struct Bench {
     pub vm: VM,
@@ -25,7 +25,7 @@ impl Bench {
         let start = std::time::Instant::now();
 
         loop {
-            if self.vm.next() == 0 || cycles >= self.max_cycles{
+            if self.vm.next() != Ok(StepResult::Continue) || cycles >= self.max_cycles{
                 break;
             };
 
@@ -1,3 +1,11 @@
+use crate::instruction::*;
+use crate::keypad::{KeyEvent, Keypad};
+use crate::quirks::Quirks;
+use crate::state::*;
+use crate::trace::{TraceEntry, Tracer};
+use std::error::Error;
+use std::fmt;
+
 static FONT: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, 0x20, 0x60, 0x20, 0x20, 0x70, 0xF0, 0x10, 0xF0, 0x80, 0xF0, 0xF0,
     0x10, 0xF0, 0x10, 0xF0, 0x90, 0x90, 0xF0, 0x10, 0x10, 0xF0, 0x80, 0xF0, 0x10, 0xF0, 0xF0, 0x80,
@@ -6,6 +14,30 @@ static FONT: [u8; 80] = [
     0xF0, 0xE0, 0x90, 0x90, 0x90, 0xE0, 0xF0, 0x80, 0xF0, 0x80, 0xF0, 0xF0, 0x80, 0xF0, 0x80, 0x80,
 ];
 
+// SUPER-CHIP 8x10 font, with the A-F digits used by XO-CHIP and Octo
+static BIG_FONT: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x18, 0x78, 0x78, 0x18, 0x18, 0x18,
+    0x18, 0x18, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF,
+    0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03,
+    0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xFF, 0xFF, 0xC0, 0xC0,
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18,
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+    0x03, 0x03, 0xFF, 0xFF, 0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xFC, 0xFC,
+    0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3,
+    0xFF, 0x3C, 0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, 0xFF, 0xFF, 0xC0, 0xC0,
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0,
+];
+
+pub const SCREEN_WIDTH: usize = 128;
+pub const SCREEN_HEIGHT: usize = 64;
+pub const PLANE_COUNT: usize = 2;
+
+// XO-CHIP extends the address space to 64 KiB
+pub const MEMORY_SIZE: usize = 0x10000;
+
+// The small font is stored from 0x50 and the big one right after it
+pub const FONT_END: usize = 0x140;
+
 fn get_bools_of_byte(byte: u8) -> [bool; 8] {
     [
         (byte & 0b10000000) >> 7 == 1,
@@ -19,7 +51,7 @@ fn get_bools_of_byte(byte: u8) -> [bool; 8] {
     ]
 }
 
-fn merge_bytes(b1: u8, b2: u8) -> u16 {
+pub fn merge_bytes(b1: u8, b2: u8) -> u16 {
     (b1 as u16) << 8 | b2 as u16
 }
 
@@ -53,28 +85,13 @@ fn bitmask4(instruction: u16) -> u16 {
     return instruction & 0x000F;
 }
 
-fn s_bitmask1(instruction: u16) -> u8 {
-    return ((instruction & 0xF000) >> 12) as u8;
-}
-
-fn s_bitmask2(instruction: u16) -> u8 {
-    return ((instruction & 0x0F00) >> 8) as u8;
-}
-
-fn s_bitmask3(instruction: u16) -> u8 {
-    return ((instruction & 0x00F0) >> 4) as u8;
-}
-
-fn s_bitmask4(instruction: u16) -> u8 {
-    return (instruction & 0x000F) as u8;
-}
-
-fn s_bitmask24(instruction: u16) -> u16 {
-    return instruction & 0x0FFF;
-}
-
-fn s_bitmask34(instruction: u16) -> u8 {
-    return (instruction & 0x00FF) as u8;
+// Registers X to Y inclusive, in descending order when Y < X (XO-CHIP 5XY2/5XY3)
+fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = u8>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
 }
 
 fn most_significant_bit(byte: u8) -> u8 {
@@ -85,75 +102,293 @@ fn least_significant_bit(byte: u8) -> u8 {
     return byte & 0b00000001;
 }
 
+// The pixel buffer is always sized for the SUPER-CHIP hi-res mode, lo-res games
+// only use its top-left 64x32 corner. Each pixel holds one bit per XO-CHIP bitplane.
 pub struct Screen {
-    pub pixels: [[bool; 32]; 64],
+    pub pixels: [[u8; SCREEN_HEIGHT]; SCREEN_WIDTH],
+    pub hires: bool,
+    // Bitmask of the planes affected by drawing, clearing and scrolling
+    pub planes: u8,
 }
 
 impl Screen {
     fn new() -> Screen {
         Screen {
-            pixels: [[false; 32]; 64],
+            pixels: [[0; SCREEN_HEIGHT]; SCREEN_WIDTH],
+            hires: false,
+            planes: 1,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            SCREEN_WIDTH
+        } else {
+            SCREEN_WIDTH / 2
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            SCREEN_HEIGHT
+        } else {
+            SCREEN_HEIGHT / 2
         }
     }
 
     fn clear(&mut self) {
-        self.pixels = [[false; 32]; 64];
+        let planes = self.planes;
+
+        for col in self.pixels.iter_mut() {
+            for pixel in col.iter_mut() {
+                *pixel &= !planes;
+            }
+        }
+    }
+
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.pixels = [[0; SCREEN_HEIGHT]; SCREEN_WIDTH];
     }
 
-    fn xor(&mut self, x: usize, y: usize, b: bool) -> bool {
-        let pixel_value = self.pixels[x][y];
-        self.pixels[x][y] = pixel_value ^ b;
+    fn xor(&mut self, x: usize, y: usize, plane: u8, b: bool) -> bool {
+        let pixel_value = self.pixels[x][y] & plane != 0;
+
+        if b {
+            self.pixels[x][y] ^= plane;
+        }
 
         return pixel_value && b;
     }
 
-    pub fn draw(&mut self, x: usize, y: usize, sprite: Vec<u8>) -> bool {
-        let mut row_counter: usize = 0;
-        let mut col_counter: usize = 0;
+    // Move the selected planes by (dx, dy), pixels scrolled in from outside are blank
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.width() as isize;
+        let height = self.height() as isize;
+        let planes = self.planes;
+        let previous = self.pixels;
+
+        for x in 0..width {
+            for y in 0..height {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let scrolled = if src_x >= 0 && src_x < width && src_y >= 0 && src_y < height {
+                    previous[src_x as usize][src_y as usize] & planes
+                } else {
+                    0
+                };
+
+                let pixel = &mut self.pixels[x as usize][y as usize];
+                *pixel = (*pixel & !planes) | scrolled;
+            }
+        }
+    }
+
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    pub fn draw(&mut self, x: usize, y: usize, sprite: Vec<u8>, clip: bool) -> bool {
+        return self.draw_with_width(x, y, sprite, 1, clip) > 0;
+    }
+
+    // Draw a sprite made of `bytes_per_row` bytes per row, 2 for SUPER-CHIP 16x16 sprites.
+    // When several planes are selected, the sprite holds the data of each plane one after the other.
+    // Returns the number of sprite rows that turned off a pixel in any plane.
+    pub fn draw_with_width(&mut self, x: usize, y: usize, sprite: Vec<u8>, bytes_per_row: usize, clip: bool) -> usize {
+        let width = self.width();
+        let height = self.height();
+
         let mut exact_x: usize;
         let mut exact_y: usize;
 
-        let mut collision = false;
+        // Bit N is set when row N collided
+        let mut colliding_rows: u32 = 0;
+
+        // The starting position always wraps, only the overflowing pixels can be clipped
+        let start_x = x % width;
+        let start_y = y % height;
+
+        let selected_planes = self.planes.count_ones() as usize;
+        if selected_planes == 0 {
+            return 0;
+        }
+        let plane_length = sprite.len() / selected_planes;
+
+        let mut plane_sprites = sprite.chunks(plane_length.max(1));
+
+        for plane in 0..PLANE_COUNT {
+            let plane_mask = 1 << plane;
+            if self.planes & plane_mask == 0 {
+                continue;
+            }
 
-        for byte in sprite {
-            let bools = get_bools_of_byte(byte);
-            for pixel in bools {
-                exact_x = (x + col_counter) % 64;
-                exact_y = (y + row_counter) % 32;
+            let plane_sprite = plane_sprites.next().unwrap_or(&[]);
 
-                collision |= self.xor(exact_x, exact_y, pixel);
+            for (row_counter, row) in plane_sprite.chunks(bytes_per_row).enumerate() {
+                for (byte_counter, byte) in row.iter().enumerate() {
+                    let bools = get_bools_of_byte(*byte);
+                    for (col_counter, pixel) in bools.iter().enumerate() {
+                        exact_x = start_x + byte_counter * 8 + col_counter;
+                        exact_y = start_y + row_counter;
 
-                col_counter += 1;
+                        if clip && (exact_x >= width || exact_y >= height) {
+                            continue;
+                        }
+
+                        if self.xor(exact_x % width, exact_y % height, plane_mask, *pixel) {
+                            colliding_rows |= 1 << row_counter;
+                        }
+                    }
+                }
             }
+        }
+
+        return colliding_rows.count_ones() as usize;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    // The frontend calls `VM::tick_timers` (or `VM::run_frame`) at 60 Hz
+    External,
+    // Timers tick every `freq / 60` instructions, for deterministic headless runs
+    Instructions,
+}
+
+// What the VM does when a program does something invalid
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    // Carry on as the original interpreter would
+    Ignore,
+    // Carry on but print the error to stderr
+    Log,
+    // Stop the VM, leaving PC on the faulting instruction
+    Halt,
+}
 
-            col_counter = 0;
-            row_counter += 1;
+impl ErrorPolicy {
+    pub fn from_name(name: &str) -> Option<ErrorPolicy> {
+        match name {
+            "ignore" => Some(ErrorPolicy::Ignore),
+            "log" => Some(ErrorPolicy::Log),
+            "halt" => Some(ErrorPolicy::Halt),
+            _ => None,
         }
+    }
+}
+
+// A memory byte or a register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Memory(u16),
+    Register(u8),
+}
 
-        return collision;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmError {
+    UnknownOpcode { address: u16, opcode: u16 },
+    // 0NNN calls to machine code routines of the host CPU
+    MachineCall { address: u16, target: u16 },
+    StackUnderflow { address: u16 },
+    // An access to `length` bytes from `start` that runs past the end of memory
+    MemoryOutOfRange { address: u16, start: u16, length: usize },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::UnknownOpcode { address, opcode } => write!(f, "Unknown opcode {:04X} at {:#05X}", opcode, address),
+            VmError::MachineCall { address, target } => {
+                write!(f, "Machine code call to {:#05X} at {:#05X}", target, address)
+            }
+            VmError::StackUnderflow { address } => write!(f, "Return with an empty stack at {:#05X}", address),
+            VmError::MemoryOutOfRange { address, start, length } => write!(
+                f,
+                "Access to {} bytes from {:#06X} past the end of memory at {:#05X}",
+                length, start, address
+            ),
+        }
     }
 }
 
+impl Error for VmError {}
+
+// State of an FX0A instruction waiting for a key, the program counter stays on it until it completes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyWait {
+    // Waiting for a key to go down. Keys already held when the wait started are
+    // ignored until they are released, so that holding a key doesn't repeat it.
+    Press { x: u8, held: u16 },
+    // A key went down, waiting for it to go up with the key release quirk
+    Release { x: u8, key: u8 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepResult {
+    Continue,
+    // The program exited with 00FD or ran off the end of memory
+    Halted,
+}
+
 pub struct VM {
-    pub memory: [u8; 4096],
+    pub memory: Vec<u8>,
     pub registers: [u8; 16],
     pub pc: u16,
     pub i: u16,
     pub stack: Vec<u16>,
     pub delay_timer: u8,
     pub sound_timer: u8,
+    pub timer_mode: TimerMode,
     timer_delay: u32,
     timer_counter: u32,
     pub screen: Screen,
     pub will_draw: bool,
-    pub keys_pressed: Vec<u8>,
-    pub custom_info: Vec<String>
+    pub keypad: Keypad,
+    // Instructions executed, the time base of key events
+    pub cycles: u64,
+    pub key_wait: Option<KeyWait>,
+    #[allow(dead_code)]
+    pub custom_info: Vec<String>,
+    pub quirks: Quirks,
+    pub rpl_flags: [u8; 16],
+    pub audio_pattern: [u8; 16],
+    pub pitch: u8,
+    pub halted: bool,
+    pub error_policy: ErrorPolicy,
+    // Most recent error, recorded whatever the policy
+    pub last_error: Option<VmError>,
+    pub rom_hash: u64,
+    // Logs executed instructions when set, not part of save states
+    pub tracer: Option<Tracer>,
+    // When set, `writes` lists the locations written by the last instruction with their
+    // previous value, even when the value didn't change
+    pub record_writes: bool,
+    pub writes: Vec<(Location, u8)>,
+    vblank: bool,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new_with_freq(freq: u32) -> VM {
         VM {
-            memory: [0; 4096],
+            memory: vec![0; MEMORY_SIZE],
             registers: [0; 16],
             pc: 512,
             i: 0,
@@ -162,10 +397,25 @@ impl VM {
             sound_timer: 0,
             screen: Screen::new(),
             will_draw: true,
-            keys_pressed: Vec::new(),
+            keypad: Keypad::new(),
+            cycles: 0,
+            key_wait: None,
             custom_info: Vec::new(),
+            timer_mode: TimerMode::External,
             timer_delay: freq / 60,
             timer_counter: 0,
+            quirks: Quirks::default(),
+            rpl_flags: [0; 16],
+            audio_pattern: [0; 16],
+            pitch: 64,
+            halted: false,
+            error_policy: ErrorPolicy::Ignore,
+            last_error: None,
+            rom_hash: 0,
+            tracer: None,
+            record_writes: false,
+            writes: Vec::new(),
+            vblank: true,
         }
     }
 
@@ -178,8 +428,157 @@ impl VM {
         self.timer_delay = freq / 60;
     }
 
-    pub fn load_rom(&mut self, rom: [u8; 4096]) {
+    pub fn set_error_policy(&mut self, error_policy: ErrorPolicy) {
+        self.error_policy = error_policy;
+    }
+
+    pub fn set_timer_mode(&mut self, timer_mode: TimerMode) {
+        self.timer_mode = timer_mode;
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Load a memory image and start executing at the address the program was loaded at
+    pub fn load_rom(&mut self, rom: Vec<u8>, load_address: u16) {
+        self.rom_hash = hash_rom(&rom);
         self.memory = rom;
+        self.pc = load_address;
+    }
+
+    // Serialize the whole machine, tagged with the hash of the loaded ROM
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        writer.bytes(STATE_MAGIC);
+        writer.u16(STATE_VERSION);
+        writer.u64(self.rom_hash);
+
+        writer.bytes(&self.memory);
+        writer.bytes(&self.registers);
+        writer.u16(self.pc);
+        writer.u16(self.i);
+        // The stack has no fixed depth, so deep recursion can go past 255 entries
+        writer.u32(self.stack.len() as u32);
+        for address in self.stack.iter() {
+            writer.u16(*address);
+        }
+
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+        writer.u32(self.timer_delay);
+        writer.u32(self.timer_counter);
+        writer.bool(self.vblank);
+
+        writer.bool(self.screen.hires);
+        writer.u8(self.screen.planes);
+        for col in self.screen.pixels.iter() {
+            writer.bytes(col);
+        }
+
+        writer.u16(self.keypad.mask());
+        match self.key_wait {
+            None => writer.u8(0),
+            Some(KeyWait::Press { x, held }) => {
+                writer.u8(1);
+                writer.u8(x);
+                writer.u16(held);
+            }
+            Some(KeyWait::Release { x, key }) => {
+                writer.u8(2);
+                writer.u8(x);
+                writer.u8(key);
+            }
+        }
+
+        writer.bytes(&self.rpl_flags);
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch);
+        writer.bool(self.halted);
+        writer.u64(self.cycles);
+
+        return writer.data;
+    }
+
+    // Restore a state made by `save_state`, the VM is left untouched on error
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data);
+
+        let rom_hash = reader.header()?;
+        if rom_hash != self.rom_hash {
+            return Err(StateError::RomMismatch { expected: self.rom_hash, found: rom_hash });
+        }
+
+        let memory = reader.bytes(MEMORY_SIZE)?.to_vec();
+        let mut registers = [0; 16];
+        registers.copy_from_slice(reader.bytes(16)?);
+        let pc = reader.u16()?;
+        let i = reader.u16()?;
+        let stack_length = reader.u32()?;
+        let mut stack = Vec::new();
+        for _ in 0..stack_length {
+            stack.push(reader.u16()?);
+        }
+
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let timer_delay = reader.u32()?;
+        let timer_counter = reader.u32()?;
+        let vblank = reader.bool()?;
+
+        let hires = reader.bool()?;
+        let planes = reader.u8()?;
+        let mut pixels = [[0; SCREEN_HEIGHT]; SCREEN_WIDTH];
+        for col in pixels.iter_mut() {
+            col.copy_from_slice(reader.bytes(SCREEN_HEIGHT)?);
+        }
+
+        let keys = reader.u16()?;
+        let key_wait = match reader.u8()? {
+            0 => None,
+            1 => Some(KeyWait::Press { x: reader.u8()? & 0xF, held: reader.u16()? }),
+            2 => Some(KeyWait::Release { x: reader.u8()? & 0xF, key: reader.u8()? & 0xF }),
+            _ => return Err(StateError::Corrupt("invalid key wait")),
+        };
+
+        let mut rpl_flags = [0; 16];
+        rpl_flags.copy_from_slice(reader.bytes(16)?);
+        let mut audio_pattern = [0; 16];
+        audio_pattern.copy_from_slice(reader.bytes(16)?);
+        let pitch = reader.u8()?;
+        let halted = reader.bool()?;
+        let cycles = reader.u64()?;
+
+        reader.finish()?;
+
+        if pc as usize >= MEMORY_SIZE - 1 {
+            return Err(StateError::Corrupt("program counter out of range"));
+        }
+
+        self.memory = memory;
+        self.registers = registers;
+        self.pc = pc;
+        self.i = i;
+        self.stack = stack;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.timer_delay = timer_delay;
+        self.timer_counter = timer_counter;
+        self.vblank = vblank;
+        self.screen.hires = hires;
+        self.screen.planes = planes;
+        self.screen.pixels = pixels;
+        self.keypad.set_mask(keys);
+        self.key_wait = key_wait;
+        self.rpl_flags = rpl_flags;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        self.halted = halted;
+        self.cycles = cycles;
+        self.will_draw = true;
+
+        return Ok(());
     }
 
     #[allow(dead_code)]
@@ -196,18 +595,78 @@ impl VM {
         );
     }
 
+    // Address of the instruction after the next one, skipping over both words of F000 NNNN
+    fn skip_pc(&self) -> u16 {
+        let next = self.pc.wrapping_add(2) as usize;
+
+        if merge_bytes(self.memory[next], self.memory[(next + 1) % MEMORY_SIZE]) == 0xF000 {
+            self.pc.saturating_add(6)
+        } else {
+            self.pc.saturating_add(4)
+        }
+    }
+
+    fn set_register(&mut self, register: u8, value: u8) {
+        if self.record_writes {
+            self.writes.push((Location::Register(register), self.registers[register as usize]));
+        }
+
+        self.registers[register as usize] = value;
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8) {
+        if self.record_writes {
+            self.writes.push((Location::Memory(address), self.memory[address as usize]));
+        }
+
+        self.memory[address as usize] = value;
+    }
+
     fn push_to_stack(&mut self, value: u16) {
         self.stack.push(value);
     }
 
-    fn pop_from_stack(&mut self) -> u16 {
-        return self.stack.pop().unwrap_or(0);
+    fn pop_from_stack(&mut self) -> Result<u16, VmError> {
+        match self.stack.pop() {
+            Some(address) => Ok(address),
+            None => {
+                self.report(VmError::StackUnderflow { address: self.pc })?;
+                Ok(0)
+            }
+        }
+    }
+
+    // Apply the error policy, only failing when the VM should halt
+    fn report(&mut self, error: VmError) -> Result<(), VmError> {
+        self.last_error = Some(error);
+
+        match self.error_policy {
+            ErrorPolicy::Ignore => Ok(()),
+            ErrorPolicy::Log => {
+                eprintln!("{}", error);
+                Ok(())
+            }
+            ErrorPolicy::Halt => Err(error),
+        }
+    }
+
+    // Accesses past the end of the platform's memory are reported
+    fn check_range(&mut self, start: u16, length: usize) -> Result<(), VmError> {
+        if start as usize + length > self.quirks.memory_size {
+            self.report(VmError::MemoryOutOfRange { address: self.pc, start, length })?;
+        }
+
+        return Ok(());
     }
 
     pub fn init_font(&mut self) {
         for i in 0x50..0xA0 {
             self.memory[i] = FONT[i - 80];
         }
+
+        for i in 0xA0..FONT_END {
+            self.memory[i] = BIG_FONT[i - 0xA0];
+        }
     }
 
     #[allow(dead_code)]
@@ -215,273 +674,507 @@ impl VM {
         let mut sprite: Vec<u8> = Vec::new();
 
         for pixels in &self.memory[(80 + byte * 5) as usize..(85 + byte * 5) as usize] {
-            sprite.push(pixels.clone());
+            sprite.push(*pixels);
         }
 
         return sprite;
     }
 
-    fn execute_instruction(&mut self, instruction: u16) -> u16 {
-        match s_bitmask1(instruction) {
-            0x0 => match instruction {
-                0x00E0 => {
-                    self.screen.clear();
-                    return self.pc + 2;
-                }
-                0x00EE => self.pop_from_stack() + 2,
-                _ => self.pc + 2,
-            },
-            0x1 => s_bitmask24(instruction),
-            0x2 => {
+    // Execute a decoded instruction and return the address of the next one.
+    // Errors are only returned when the error policy halts the VM.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<u16, VmError> {
+        let new_pc = match instruction {
+            Instruction::Sys(target) => {
+                self.report(VmError::MachineCall { address: self.pc, target })?;
+                self.pc.saturating_add(2)
+            }
+            Instruction::Cls => {
+                self.screen.clear();
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Ret => self.pop_from_stack()?.saturating_add(2),
+            Instruction::ScrollDown(n) => {
+                self.screen.scroll_down(n as usize);
+                self.will_draw = true;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::ScrollUp(n) => {
+                self.screen.scroll_up(n as usize);
+                self.will_draw = true;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::ScrollRight => {
+                self.screen.scroll_right(4);
+                self.will_draw = true;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::ScrollLeft => {
+                self.screen.scroll_left(4);
+                self.will_draw = true;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Exit => {
+                self.halted = true;
+                return Ok(self.pc);
+            }
+            Instruction::Low => {
+                self.screen.set_hires(false);
+                self.will_draw = true;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::High => {
+                self.screen.set_hires(true);
+                self.will_draw = true;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Jp(address) => address,
+            Instruction::Call(address) => {
                 self.push_to_stack(self.pc);
-                return s_bitmask24(instruction);
+                return Ok(address);
             }
-            0x3 => {
-                if self.registers[s_bitmask2(instruction) as usize] == s_bitmask34(instruction) {
-                    self.pc + 4
+            Instruction::SeImm(x, nn) => {
+                if self.registers[x as usize] == nn {
+                    self.skip_pc()
                 } else {
-                    self.pc + 2
+                    self.pc.saturating_add(2)
                 }
             }
-            0x4 => {
-                if self.registers[s_bitmask2(instruction) as usize] == s_bitmask34(instruction) {
-                    self.pc + 2
+            Instruction::SneImm(x, nn) => {
+                if self.registers[x as usize] == nn {
+                    self.pc.saturating_add(2)
                 } else {
-                    self.pc + 4
+                    self.skip_pc()
                 }
             }
-            0x5 => match bitmask4(instruction) {
-                0x0 => {
-                    if self.registers[s_bitmask2(instruction) as usize]
-                        == self.registers[s_bitmask3(instruction) as usize]
-                    {
-                        self.pc + 4
-                    } else {
-                        self.pc + 2
-                    }
-                }
-                _ => self.pc + 2,
-            },
-            0x6 => {
-                self.registers[s_bitmask2(instruction) as usize] = s_bitmask34(instruction);
-                return self.pc + 2;
-            }
-            0x7 => {
-                self.registers[s_bitmask2(instruction) as usize] = self.registers
-                    [s_bitmask2(instruction) as usize]
-                    .overflowing_add(s_bitmask34(instruction))
-                    .0;
-                return self.pc + 2;
-            }
-            0x8 => match s_bitmask4(instruction) {
-                0x0 => {
-                    self.registers[s_bitmask2(instruction) as usize] =
-                        self.registers[s_bitmask3(instruction) as usize];
-                    return self.pc + 2;
-                }
-                0x1 => {
-                    self.registers[s_bitmask2(instruction) as usize] |=
-                        self.registers[s_bitmask3(instruction) as usize];
-                    return self.pc + 2;
-                }
-                0x2 => {
-                    self.registers[s_bitmask2(instruction) as usize] &=
-                        self.registers[s_bitmask3(instruction) as usize];
-                    return self.pc + 2;
-                }
-                0x3 => {
-                    self.registers[s_bitmask2(instruction) as usize] ^=
-                        self.registers[s_bitmask3(instruction) as usize];
-                    return self.pc + 2;
+            Instruction::SeReg(x, y) => {
+                if self.registers[x as usize] == self.registers[y as usize] {
+                    self.skip_pc()
+                } else {
+                    self.pc.saturating_add(2)
                 }
-                0x4 => {
-                    let bitmask = s_bitmask2(instruction);
-                    let (result, overflow) = self.registers[bitmask as usize]
-                        .overflowing_add(self.registers[s_bitmask3(instruction) as usize]);
-                    self.registers[0xF] = if overflow { 1 } else { 0 };
-                    self.registers[bitmask as usize] = result;
-                    return self.pc + 2;
+            }
+            Instruction::SaveRange(x, y) => {
+                self.check_range(self.i, register_range(x, y).count())?;
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.write_memory(self.i.wrapping_add(offset as u16), self.registers[register as usize]);
                 }
-                0x5 => {
-                    let bitmask = s_bitmask2(instruction);
-                    let (result, overflow) = self.registers[bitmask as usize]
-                        .overflowing_sub(self.registers[s_bitmask3(instruction) as usize]);
-                    self.registers[0xF] = if overflow { 0 } else { 1 };
-                    self.registers[bitmask as usize] = result;
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LoadRange(x, y) => {
+                self.check_range(self.i, register_range(x, y).count())?;
+                for (offset, register) in register_range(x, y).enumerate() {
+                    self.set_register(register, self.memory[self.i.wrapping_add(offset as u16) as usize]);
                 }
-                0x6 => {
-                    let bitmask = s_bitmask2(instruction);
-                    self.registers[0xF] = least_significant_bit(self.registers[bitmask as usize]);
-                    self.registers[bitmask as usize] >>= 1;
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdImm(x, nn) => {
+                self.set_register(x, nn);
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::AddImm(x, nn) => {
+                self.set_register(x, self.registers[x as usize].overflowing_add(nn).0);
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdReg(x, y) => {
+                self.set_register(x, self.registers[y as usize]);
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Or(x, y) => {
+                self.set_register(x, self.registers[x as usize] | self.registers[y as usize]);
+                if self.quirks.vf_reset {
+                    self.set_register(0xF, 0);
                 }
-                0x7 => {
-                    let bitmask = s_bitmask2(instruction);
-                    let (result, overflow) = self.registers[s_bitmask3(instruction) as usize]
-                        .overflowing_sub(self.registers[bitmask as usize]);
-                    self.registers[0xF] = if overflow { 0 } else { 1 };
-                    self.registers[bitmask as usize] = result;
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::And(x, y) => {
+                self.set_register(x, self.registers[x as usize] & self.registers[y as usize]);
+                if self.quirks.vf_reset {
+                    self.set_register(0xF, 0);
                 }
-                0xE => {
-                    let bitmask = s_bitmask2(instruction);
-                    self.registers[0xF] = most_significant_bit(self.registers[bitmask as usize]);
-                    self.registers[bitmask as usize] <<= 1;
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Xor(x, y) => {
+                self.set_register(x, self.registers[x as usize] ^ self.registers[y as usize]);
+                if self.quirks.vf_reset {
+                    self.set_register(0xF, 0);
                 }
-                _ => self.pc + 2,
-            },
-            0x9 => match s_bitmask4(instruction) {
-                0x0 => {
-                    if self.registers[s_bitmask2(instruction) as usize]
-                        == self.registers[s_bitmask3(instruction) as usize]
-                    {
-                        self.pc + 2
-                    } else {
-                        self.pc + 4
-                    }
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::AddReg(x, y) => {
+                let (result, overflow) = self.registers[x as usize].overflowing_add(self.registers[y as usize]);
+                // The flag is written last, so it wins when X is F
+                self.set_register(x, result);
+                self.set_register(0xF, if overflow { 1 } else { 0 });
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Sub(x, y) => {
+                let (result, overflow) = self.registers[x as usize].overflowing_sub(self.registers[y as usize]);
+                self.set_register(x, result);
+                self.set_register(0xF, if overflow { 0 } else { 1 });
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Shr(x, y) => {
+                let source = if self.quirks.shift { x } else { y };
+                let value = self.registers[source as usize];
+                self.set_register(x, value >> 1);
+                self.set_register(0xF, least_significant_bit(value));
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Subn(x, y) => {
+                let (result, overflow) = self.registers[y as usize].overflowing_sub(self.registers[x as usize]);
+                self.set_register(x, result);
+                self.set_register(0xF, if overflow { 0 } else { 1 });
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Shl(x, y) => {
+                let source = if self.quirks.shift { x } else { y };
+                let value = self.registers[source as usize];
+                self.set_register(x, value << 1);
+                self.set_register(0xF, most_significant_bit(value));
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::SneReg(x, y) => {
+                if self.registers[x as usize] == self.registers[y as usize] {
+                    self.pc.saturating_add(2)
+                } else {
+                    self.skip_pc()
                 }
-                _ => self.pc + 2,
-            },
-            0xA => {
-                self.i = s_bitmask24(instruction);
-                return self.pc + 2;
             }
-            0xB => s_bitmask24(instruction) + self.registers[0x0] as u16,
-            0xC => {
-                self.registers[s_bitmask2(instruction) as usize] =
-                    fastrand::u8(..) & s_bitmask34(instruction);
-                return self.pc + 2;
+            Instruction::LdI(address) => {
+                self.i = address;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::JpV0(address) => {
+                // With the jump quirk, BXNN adds VX where X is the top nibble of the address
+                let offset_register = if self.quirks.jump {
+                    (address >> 8) as u8
+                } else {
+                    0x0
+                };
+                return Ok(address + self.registers[offset_register as usize] as u16);
+            }
+            Instruction::Rnd(x, nn) => {
+                self.set_register(x, fastrand::u8(..) & nn);
+                return Ok(self.pc.saturating_add(2));
             }
-            0xD => {
+            Instruction::Drw(x, y, n) => {
+                // Drawing is only allowed once per frame when waiting for the vertical blank
+                if self.quirks.display_wait && !self.vblank {
+                    return Ok(self.pc);
+                }
+
                 let mut sprite: Vec<u8> = Vec::new();
 
-                for i in 0..s_bitmask4(instruction) {
-                    sprite.push(self.memory[(self.i + (i as u16)) as usize]);
+                // DXY0 draws a 16x16 sprite, stored as 2 bytes per row
+                let (rows, bytes_per_row) = match n {
+                    0 => (16, 2),
+                    n => (n as u16, 1),
+                };
+                let planes = self.screen.planes.count_ones() as u16;
+                self.check_range(self.i, (rows * bytes_per_row * planes) as usize)?;
+
+                for i in 0..rows * bytes_per_row * planes {
+                    sprite.push(self.memory[self.i.wrapping_add(i) as usize]);
                 }
 
-                self.registers[0xF] = if self.screen.draw(
-                    self.registers[s_bitmask2(instruction) as usize] as usize,
-                    self.registers[s_bitmask3(instruction) as usize] as usize,
+                let colliding_rows = self.screen.draw_with_width(
+                    self.registers[x as usize] as usize,
+                    self.registers[y as usize] as usize,
                     sprite,
-                ) {
-                    1
+                    bytes_per_row as usize,
+                    self.quirks.clipping,
+                );
+
+                if self.quirks.collision_rows && self.screen.hires {
+                    let height = self.screen.height();
+                    let start_y = self.registers[y as usize] as usize % height;
+                    let clipped_rows = if self.quirks.clipping {
+                        (start_y + rows as usize).saturating_sub(height)
+                    } else {
+                        0
+                    };
+                    self.set_register(0xF, (colliding_rows + clipped_rows) as u8);
                 } else {
-                    0
-                };
+                    self.set_register(0xF, if colliding_rows > 0 { 1 } else { 0 });
+                }
 
                 self.will_draw = true;
+                self.vblank = false;
 
-                return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
             }
-            0xE => match s_bitmask34(instruction) {
-                0x9E => {
-                    if self.keys_pressed.contains(&s_bitmask2(instruction)) {
-                        self.pc + 4
-                    } else {
-                        self.pc + 2
-                    }
-                }
-                0xA1 => {
-                    if self.keys_pressed.contains(&s_bitmask2(instruction)) {
-                        self.pc + 2
-                    } else {
-                        self.pc + 4
-                    }
-                }
-                _ => self.pc + 2,
-            },
-            0xF => match s_bitmask34(instruction) {
-                0x07 => {
-                    self.registers[s_bitmask2(instruction) as usize] = self.delay_timer;
-                    return self.pc + 2;
+            Instruction::Skp(x) => {
+                if self.keypad.is_down(self.registers[x as usize]) {
+                    self.skip_pc()
+                } else {
+                    self.pc.saturating_add(2)
                 }
-                0x0A => {
-                    if self.keys_pressed.is_empty() {
-                        return self.pc;
-                    } else {
-                        self.registers[s_bitmask2(instruction) as usize] =
-                            self.keys_pressed.pop().unwrap();
-                        return self.pc + 2;
-                    }
+            }
+            Instruction::Sknp(x) => {
+                if self.keypad.is_down(self.registers[x as usize]) {
+                    self.pc.saturating_add(2)
+                } else {
+                    self.skip_pc()
                 }
-                0x15 => {
-                    self.delay_timer = self.registers[s_bitmask2(instruction) as usize];
-                    return self.pc + 2;
+            }
+            Instruction::LdILong => {
+                let address = self.pc.wrapping_add(2) as usize;
+                self.i = merge_bytes(self.memory[address], self.memory[(address + 1) % MEMORY_SIZE]);
+                return Ok(self.pc.saturating_add(4));
+            }
+            Instruction::Plane(x) => {
+                self.screen.planes = x & 0b11;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Audio => {
+                self.check_range(self.i, 16)?;
+                for i in 0..16 {
+                    self.audio_pattern[i] = self.memory[self.i.wrapping_add(i as u16) as usize];
                 }
-                0x18 => {
-                    self.sound_timer = self.registers[s_bitmask2(instruction) as usize];
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdVxDt(x) => {
+                self.set_register(x, self.delay_timer);
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdVxK(x) => {
+                self.key_wait = Some(KeyWait::Press { x, held: self.keypad.mask() });
+                return Ok(self.pc);
+            }
+            Instruction::LdDtVx(x) => {
+                self.delay_timer = self.registers[x as usize];
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdStVx(x) => {
+                self.sound_timer = self.registers[x as usize];
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::AddI(x) => {
+                self.i = self.i.overflowing_add(self.registers[x as usize] as u16).0;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdF(x) => {
+                self.i = 80 + 5 * (self.registers[x as usize] as u16);
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdHf(x) => {
+                self.i = 0xA0 + 10 * (self.registers[x as usize] as u16);
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdB(x) => {
+                self.check_range(self.i, 3)?;
+                let value = self.registers[x as usize];
+                self.write_memory(self.i, value / 100);
+                self.write_memory(self.i.wrapping_add(1), value / 10 % 10);
+                self.write_memory(self.i.wrapping_add(2), value % 10);
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::Pitch(x) => {
+                self.pitch = self.registers[x as usize];
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdIVx(x) => {
+                self.check_range(self.i, x as usize + 1)?;
+                for i in 0..x + 1 {
+                    self.write_memory(self.i.wrapping_add(i as u16), self.registers[i as usize]);
                 }
-                0x1E => {
-                    self.i = self
-                        .i
-                        .overflowing_add(self.registers[s_bitmask2(instruction) as usize] as u16)
-                        .0;
-                    return self.pc + 2;
+                if self.quirks.load_store {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
-                0x29 => {
-                    self.i = 80 + 5 * (self.registers[s_bitmask2(instruction) as usize] as u16);
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdVxI(x) => {
+                self.check_range(self.i, x as usize + 1)?;
+                for i in 0..x + 1 {
+                    self.set_register(i, self.memory[self.i.wrapping_add(i as u16) as usize]);
                 }
-                0x33 => {
-                    let value = self.registers[s_bitmask2(instruction) as usize];
-                    self.memory[self.i as usize] = value / 100;
-                    self.memory[self.i as usize + 1] = value / 10 % 10;
-                    self.memory[self.i as usize + 2] = value % 10;
-                    return self.pc + 2;
+                if self.quirks.load_store {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
-                0x55 => {
-                    for i in 0..s_bitmask2(instruction) + 1 {
-                        self.memory[(self.i + i as u16) as usize] = self.registers[i as usize];
-                    }
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdRVx(x) => {
+                for i in 0..x + 1 {
+                    self.rpl_flags[i as usize] = self.registers[i as usize];
                 }
-                0x65 => {
-                    for i in 0..s_bitmask2(instruction) + 1 {
-                        self.registers[i as usize] = self.memory[(self.i + i as u16) as usize];
-                    }
-                    return self.pc + 2;
+                return Ok(self.pc.saturating_add(2));
+            }
+            Instruction::LdVxR(x) => {
+                for i in 0..x + 1 {
+                    self.set_register(i, self.rpl_flags[i as usize]);
                 }
-                _ => self.pc + 2,
-            },
-            _ => self.pc + 2,
-        }
+                self.pc.saturating_add(2)
+            }
+        };
+
+        return Ok(new_pc);
     }
 
     #[allow(dead_code)]
     fn test_keys_and_screen(&mut self) {
-        for key in self.keys_pressed.iter() {
+        for key in self.keypad.keys() {
             let mut sprite: Vec<u8> = Vec::new();
 
             for i in 80 + key * 5..85 + key * 5 {
                 sprite.push(self.memory[i as usize]);
             }
 
-            self.screen.draw(1, 1, sprite);
+            self.screen.draw(1, 1, sprite, false);
         }
     }
 
-    pub fn next(&mut self) -> u8 {
-        if self.timer_counter == self.timer_delay {
-            self.delay_timer = self.delay_timer.saturating_sub(1);
-            self.sound_timer = self.sound_timer.saturating_sub(1);
+    // Queue a key press, seen by the instructions from the current cycle on
+    pub fn key_down(&mut self, key: u8) {
+        self.keypad.push(KeyEvent { cycle: self.cycles, key, pressed: true });
+    }
+
+    pub fn key_up(&mut self, key: u8) {
+        self.keypad.push(KeyEvent { cycle: self.cycles, key, pressed: false });
+    }
+
+    // Advance a pending FX0A, which completes on the press of a key, or on its
+    // release with the key release quirk. Returns true once it has completed.
+    fn update_key_wait(&mut self, wait: KeyWait) -> bool {
+        let keys = self.keypad.mask();
+
+        let (x, key) = match wait {
+            KeyWait::Press { x, held } => {
+                let pressed = keys & !held;
+                if pressed == 0 {
+                    // Released keys count again when they are pressed anew
+                    self.key_wait = Some(KeyWait::Press { x, held: held & keys });
+                    return false;
+                }
+
+                let key = pressed.trailing_zeros() as u8;
+                if self.quirks.key_release {
+                    self.key_wait = Some(KeyWait::Release { x, key });
+                    return false;
+                }
+
+                (x, key)
+            }
+            KeyWait::Release { x, key } => {
+                if keys & 1 << key != 0 {
+                    return false;
+                }
 
-            self.timer_counter = 0;
+                (x, key)
+            }
+        };
+
+        self.set_register(x, key);
+        self.key_wait = None;
+
+        return true;
+    }
+
+    // Log the instruction that just ran at PC, FX0A shows up again once it gets its key
+    fn trace(&mut self, opcode: u16, registers: &[u8; 16]) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            if tracer.traces(self.pc, opcode) {
+                tracer.record(TraceEntry::new(self.cycles - 1, self.pc, opcode, self.i, registers, &self.registers));
+            }
         }
+    }
 
-        self.timer_counter += 1;
+    fn halt(&mut self) {
+        self.halted = true;
 
-        let instruction = self.get_instruction();
-        let new_pc = self.execute_instruction(instruction);
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.finish();
+        }
+    }
 
-        if new_pc > 2047 {
-            return 0;
+    // Decrement the delay and sound timers, meant to be called at 60 Hz
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+        self.vblank = true;
+    }
+
+    // Execute one frame worth of instructions then tick the timers once
+    pub fn run_frame(&mut self, cycles_per_frame: u32) -> Result<StepResult, VmError> {
+        for _ in 0..cycles_per_frame {
+            if self.next()? == StepResult::Halted {
+                return Ok(StepResult::Halted);
+            }
+        }
+
+        if self.timer_mode == TimerMode::External {
+            self.tick_timers();
+        }
+
+        return Ok(StepResult::Continue);
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<StepResult, VmError> {
+        self.writes.clear();
+
+        if self.halted {
+            return Ok(StepResult::Halted);
+        }
+
+        if self.timer_mode == TimerMode::Instructions {
+            if self.timer_counter == self.timer_delay {
+                self.tick_timers();
+                self.timer_counter = 0;
+            }
+
+            self.timer_counter += 1;
+        }
+
+        self.keypad.update(self.cycles);
+        self.cycles += 1;
+
+        let opcode = self.get_instruction();
+        let registers = self.registers;
+
+        let result = match self.key_wait {
+            // Timers keep running while FX0A waits for a key
+            Some(wait) => {
+                if !self.update_key_wait(wait) {
+                    return Ok(StepResult::Continue);
+                }
+
+                Ok(self.pc.saturating_add(2))
+            }
+            None => match decode(opcode) {
+                Ok(instruction) => self.execute(instruction),
+                Err(_) => self
+                    .report(VmError::UnknownOpcode { address: self.pc, opcode })
+                    .map(|_| self.pc.saturating_add(2)),
+            },
+        };
+
+        self.trace(opcode, &registers);
+
+        let new_pc = match result {
+            Ok(new_pc) => new_pc,
+            Err(error) => {
+                self.halt();
+                return Err(error);
+            }
+        };
+
+        if self.halted {
+            self.halt();
+            return Ok(StepResult::Halted);
+        }
+
+        // Halt when the program runs off the end of the address space. Addresses
+        // past it saturate to 0xFFFF instead of wrapping around to 0.
+        if new_pc as usize >= MEMORY_SIZE - 1 {
+            self.halt();
+            return Ok(StepResult::Halted);
         }
 
         self.pc = new_pc;
 
-        return 1;
+        return Ok(StepResult::Continue);
     }
 }
+
+#[cfg(test)]
+mod tests;
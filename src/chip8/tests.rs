@@ -0,0 +1,981 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use proptest::prelude::*;
+
+use super::*;
+use crate::debugger::{Debugger, Watchpoint};
+use crate::trace::TraceSettings;
+
+// Sets up a VM, then runs the program one instruction at a time
+#[derive(Default)]
+struct VmBuilder {
+    registers: Vec<(u8, u8)>,
+    program: Vec<u16>,
+    memory: Vec<(u16, Vec<u8>)>,
+    stack: Vec<u16>,
+    keys: Vec<u8>,
+    i: u16,
+    quirks: Quirks,
+    error_policy: Option<ErrorPolicy>,
+}
+
+impl VmBuilder {
+    fn new() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    // Registers as (index, value) pairs, the others start at 0
+    fn with_registers(mut self, registers: &[(u8, u8)]) -> VmBuilder {
+        self.registers.extend_from_slice(registers);
+        return self;
+    }
+
+    // Opcodes stored from 0x200, where the VM starts
+    fn with_program(mut self, program: &[u16]) -> VmBuilder {
+        self.program.extend_from_slice(program);
+        return self;
+    }
+
+    fn with_memory(mut self, address: u16, bytes: &[u8]) -> VmBuilder {
+        self.memory.push((address, bytes.to_vec()));
+        return self;
+    }
+
+    fn with_stack(mut self, stack: &[u16]) -> VmBuilder {
+        self.stack.extend_from_slice(stack);
+        return self;
+    }
+
+    fn with_keys(mut self, keys: &[u8]) -> VmBuilder {
+        self.keys.extend_from_slice(keys);
+        return self;
+    }
+
+    fn with_i(mut self, i: u16) -> VmBuilder {
+        self.i = i;
+        return self;
+    }
+
+    fn with_quirks(mut self, quirks: Quirks) -> VmBuilder {
+        self.quirks = quirks;
+        return self;
+    }
+
+    fn with_error_policy(mut self, error_policy: ErrorPolicy) -> VmBuilder {
+        self.error_policy = Some(error_policy);
+        return self;
+    }
+
+    fn build(self) -> VM {
+        let mut vm = VM::new();
+        vm.init_font();
+        vm.set_quirks(self.quirks);
+        vm.i = self.i;
+        vm.stack = self.stack;
+
+        if let Some(error_policy) = self.error_policy {
+            vm.set_error_policy(error_policy);
+        }
+
+        for (x, value) in self.registers {
+            vm.registers[x as usize] = value;
+        }
+
+        for (n, opcode) in self.program.iter().enumerate() {
+            let address = 0x200 + n * 2;
+            vm.memory[address] = (opcode >> 8) as u8;
+            vm.memory[address + 1] = *opcode as u8;
+        }
+
+        for (address, bytes) in self.memory {
+            let start = address as usize;
+            vm.memory[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        for key in self.keys {
+            vm.keypad.push(KeyEvent { cycle: 0, key, pressed: true });
+        }
+
+        return vm;
+    }
+
+    // Build the VM and execute its first instruction
+    fn run(self) -> VM {
+        let mut vm = self.build();
+        vm.next().expect("the instruction failed");
+
+        return vm;
+    }
+}
+
+fn quirks(shift: bool, vf_reset: bool) -> Quirks {
+    Quirks { shift, vf_reset, ..Quirks::chipr() }
+}
+
+#[test]
+fn cls_clears_the_screen() {
+    let mut vm = VmBuilder::new().with_program(&[0x00E0]).build();
+    vm.screen.pixels[3][4] = 1;
+    vm.next().unwrap();
+
+    assert_eq!(vm.screen.pixels[3][4], 0);
+    assert_eq!(vm.pc, 0x202);
+}
+
+#[test]
+fn call_pushes_the_return_address() {
+    let vm = VmBuilder::new().with_program(&[0x2468]).run();
+
+    assert_eq!(vm.pc, 0x468);
+    assert_eq!(vm.stack, vec![0x200]);
+}
+
+#[test]
+fn ret_returns_after_the_call() {
+    let vm = VmBuilder::new().with_program(&[0x00EE]).with_stack(&[0x300]).run();
+
+    assert_eq!(vm.pc, 0x302);
+    assert!(vm.stack.is_empty());
+}
+
+#[test]
+fn ret_with_an_empty_stack_halts() {
+    let mut vm = VmBuilder::new().with_program(&[0x00EE]).with_error_policy(ErrorPolicy::Halt).build();
+
+    assert_eq!(vm.next(), Err(VmError::StackUnderflow { address: 0x200 }));
+    assert!(vm.halted);
+}
+
+#[test]
+fn jp_jumps() {
+    let vm = VmBuilder::new().with_program(&[0x1ABC]).run();
+
+    assert_eq!(vm.pc, 0xABC);
+}
+
+#[test]
+fn skips_compare_immediates_and_registers() {
+    let cases: &[(u16, u16)] = &[
+        (0x3142, 0x204),
+        (0x3143, 0x202),
+        (0x4142, 0x202),
+        (0x4143, 0x204),
+        (0x5120, 0x204),
+        (0x5130, 0x202),
+        (0x9120, 0x202),
+        (0x9130, 0x204),
+    ];
+
+    for (opcode, pc) in cases {
+        let vm = VmBuilder::new().with_registers(&[(1, 0x42), (2, 0x42), (3, 0x7)]).with_program(&[*opcode]).run();
+
+        assert_eq!(vm.pc, *pc, "{:04X}", opcode);
+    }
+}
+
+#[test]
+fn skips_over_long_instructions() {
+    let vm = VmBuilder::new().with_program(&[0x3000, 0xF000, 0x1234]).run();
+
+    assert_eq!(vm.pc, 0x206);
+}
+
+#[test]
+fn ld_and_add_immediate() {
+    let vm = VmBuilder::new().with_program(&[0x6A42]).run();
+    assert_eq!(vm.registers[0xA], 0x42);
+
+    // 7XNN wraps around and leaves VF alone
+    let vm = VmBuilder::new().with_registers(&[(1, 0xFF), (0xF, 0x5)]).with_program(&[0x7102]).run();
+    assert_eq!(vm.registers[1], 0x01);
+    assert_eq!(vm.registers[0xF], 0x5);
+}
+
+#[test]
+fn ld_copies_a_register() {
+    let vm = VmBuilder::new().with_registers(&[(2, 0x99)]).with_program(&[0x8120]).run();
+
+    assert_eq!(vm.registers[1], 0x99);
+}
+
+#[test]
+fn logic_ops_reset_vf_with_the_quirk() {
+    for (opcode, expected) in [(0x8121, 0b1110), (0x8122, 0b1000), (0x8123, 0b0110)] {
+        let builder = || VmBuilder::new().with_registers(&[(1, 0b1100), (2, 0b1010), (0xF, 0x7)]).with_program(&[opcode]);
+
+        let vm = builder().run();
+        assert_eq!(vm.registers[1], expected, "{:04X}", opcode);
+        assert_eq!(vm.registers[0xF], 0x7, "{:04X}", opcode);
+
+        let vm = builder().with_quirks(Quirks::cosmac_vip()).run();
+        assert_eq!(vm.registers[1], expected, "{:04X}", opcode);
+        assert_eq!(vm.registers[0xF], 0, "{:04X}", opcode);
+    }
+}
+
+#[test]
+fn add_sets_the_carry() {
+    let vm = VmBuilder::new().with_registers(&[(1, 0xF0), (2, 0x20)]).with_program(&[0x8124]).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x10, 1));
+
+    let vm = VmBuilder::new().with_registers(&[(1, 0x10), (2, 0x20), (0xF, 1)]).with_program(&[0x8124]).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x30, 0));
+}
+
+#[test]
+fn sub_clears_vf_on_borrow() {
+    let vm = VmBuilder::new().with_registers(&[(1, 0x10), (2, 0x20)]).with_program(&[0x8125]).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0xF0, 0));
+
+    // No borrow when both are equal
+    let vm = VmBuilder::new().with_registers(&[(1, 0x20), (2, 0x20)]).with_program(&[0x8125]).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x00, 1));
+}
+
+#[test]
+fn subn_subtracts_vx_from_vy() {
+    let vm = VmBuilder::new().with_registers(&[(1, 0x10), (2, 0x30)]).with_program(&[0x8127]).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x20, 1));
+
+    let vm = VmBuilder::new().with_registers(&[(1, 0x30), (2, 0x10)]).with_program(&[0x8127]).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0xE0, 0));
+}
+
+#[test]
+fn flag_wins_when_x_is_f() {
+    let cases: &[(u16, u8, u8, u8)] = &[
+        // Opcode, VF, V1, VF afterwards
+        (0x8F14, 0x30, 0x00, 0),
+        (0x8F14, 0xF0, 0x20, 1),
+        (0x8F15, 0x30, 0x10, 1),
+        (0x8F15, 0x10, 0x30, 0),
+        (0x8F17, 0x10, 0x30, 1),
+        (0x8F17, 0x30, 0x10, 0),
+        (0x8F16, 0x03, 0x00, 1),
+        (0x8F1E, 0x40, 0x00, 0),
+    ];
+
+    for (opcode, vf, v1, flag) in cases {
+        let vm = VmBuilder::new().with_registers(&[(0xF, *vf), (1, *v1)]).with_program(&[*opcode]).run();
+
+        assert_eq!(vm.registers[0xF], *flag, "{:04X} with VF={:02X} V1={:02X}", opcode, vf, v1);
+    }
+}
+
+#[test]
+fn shifts_use_vy_without_the_quirk() {
+    let builder = |opcode| VmBuilder::new().with_registers(&[(1, 0x81), (2, 0x42)]).with_program(&[opcode]);
+
+    let vm = builder(0x8126).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x40, 1));
+    let vm = builder(0x812E).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x02, 1));
+
+    let vm = builder(0x8126).with_quirks(Quirks::cosmac_vip()).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x21, 0));
+    let vm = builder(0x812E).with_quirks(Quirks::cosmac_vip()).run();
+    assert_eq!((vm.registers[1], vm.registers[0xF]), (0x84, 0));
+}
+
+#[test]
+fn ld_i_and_add_i() {
+    let vm = VmBuilder::new().with_program(&[0xA123]).run();
+    assert_eq!(vm.i, 0x123);
+
+    let vm = VmBuilder::new().with_registers(&[(3, 0x10)]).with_i(0xFFF8).with_program(&[0xF31E]).run();
+    assert_eq!(vm.i, 0x0008);
+}
+
+#[test]
+fn jp_v0_adds_a_register() {
+    let vm = VmBuilder::new().with_registers(&[(0, 0x02), (3, 0x10)]).with_program(&[0xB300]).run();
+    assert_eq!(vm.pc, 0x302);
+
+    // BXNN adds VX, X being the top nibble of the address
+    let vm = VmBuilder::new()
+        .with_registers(&[(0, 0x02), (3, 0x10)])
+        .with_quirks(Quirks::schip_modern())
+        .with_program(&[0xB300])
+        .run();
+    assert_eq!(vm.pc, 0x310);
+}
+
+#[test]
+fn rnd_masks_the_random_byte() {
+    for _ in 0..32 {
+        let vm = VmBuilder::new().with_program(&[0xC10F]).run();
+
+        assert_eq!(vm.registers[1] & 0xF0, 0);
+    }
+}
+
+#[test]
+fn drw_reports_collisions() {
+    let mut vm = VmBuilder::new()
+        .with_registers(&[(1, 2), (2, 3)])
+        .with_i(0x300)
+        .with_memory(0x300, &[0b11000000])
+        .with_program(&[0xD121, 0xD121])
+        .build();
+
+    vm.next().unwrap();
+    assert_eq!(vm.screen.pixels[2][3], 1);
+    assert_eq!(vm.screen.pixels[3][3], 1);
+    assert_eq!(vm.registers[0xF], 0);
+
+    vm.next().unwrap();
+    assert_eq!(vm.screen.pixels[2][3], 0);
+    assert_eq!(vm.registers[0xF], 1);
+}
+
+#[test]
+fn hires_drw_counts_colliding_and_clipped_rows_with_the_quirk() {
+    let legacy = Quirks { display_wait: false, ..Quirks::schip_legacy() };
+    // 00FF, then a 16x16 sprite drawn twice at (0, 0) and once at (0, 60)
+    let builder = |quirks| {
+        VmBuilder::new()
+            .with_quirks(quirks)
+            .with_registers(&[(2, 60)])
+            .with_i(0x300)
+            .with_memory(0x300, &[0xFF; 32])
+            .with_program(&[0x00FF, 0xD010, 0xD010, 0xD020])
+            .build()
+    };
+
+    let mut vm = builder(legacy);
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.registers[0xF], 0);
+    vm.next().unwrap();
+    assert_eq!(vm.registers[0xF], 16);
+    // Rows 60 to 63 are drawn, the 12 others are clipped
+    vm.next().unwrap();
+    assert_eq!(vm.registers[0xF], 12);
+
+    let mut vm = builder(Quirks::schip_modern());
+    for _ in 0..3 {
+        vm.next().unwrap();
+    }
+    assert_eq!(vm.registers[0xF], 1);
+    vm.next().unwrap();
+    assert_eq!(vm.registers[0xF], 0);
+
+    // Lo-res drawing only reports whether there was a collision
+    let mut vm = VmBuilder::new().with_quirks(legacy).with_i(0x50).with_program(&[0xD015, 0xD015]).build();
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.registers[0xF], 1);
+}
+
+#[test]
+fn drw_waits_for_the_vertical_blank_with_the_quirk() {
+    let mut vm = VmBuilder::new().with_quirks(Quirks::cosmac_vip()).with_program(&[0xD001, 0xD001]).build();
+
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x202);
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x202);
+
+    vm.tick_timers();
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x204);
+}
+
+#[test]
+fn key_skips() {
+    let builder = |opcode| VmBuilder::new().with_registers(&[(1, 0xA)]).with_program(&[opcode]);
+
+    assert_eq!(builder(0xE19E).with_keys(&[0xA]).run().pc, 0x204);
+    assert_eq!(builder(0xE19E).run().pc, 0x202);
+    assert_eq!(builder(0xE1A1).with_keys(&[0xA]).run().pc, 0x202);
+    assert_eq!(builder(0xE1A1).run().pc, 0x204);
+    // Key 1 is the register number, which must not count
+    assert_eq!(builder(0xE19E).with_keys(&[0x1]).run().pc, 0x202);
+    assert_eq!(builder(0xE1A1).with_keys(&[0x1]).run().pc, 0x204);
+}
+
+#[test]
+fn timers_load_and_store() {
+    let mut vm = VmBuilder::new().with_registers(&[(1, 0x30)]).with_program(&[0xF115, 0xF218, 0xF307]).build();
+
+    vm.next().unwrap();
+    vm.registers[2] = 0x40;
+    vm.next().unwrap();
+    vm.next().unwrap();
+
+    assert_eq!(vm.delay_timer, 0x30);
+    assert_eq!(vm.sound_timer, 0x40);
+    assert_eq!(vm.registers[3], 0x30);
+}
+
+#[test]
+fn font_sprites() {
+    let vm = VmBuilder::new().with_registers(&[(1, 0xA)]).with_program(&[0xF129]).run();
+    assert_eq!(vm.i, 0x50 + 5 * 0xA);
+    assert_eq!(&vm.memory[vm.i as usize..vm.i as usize + 5], &FONT[50..55]);
+
+    let vm = VmBuilder::new().with_registers(&[(1, 0x3)]).with_program(&[0xF130]).run();
+    assert_eq!(vm.i, 0xA0 + 10 * 0x3);
+}
+
+#[test]
+fn bcd_writes_the_three_digits() {
+    for value in 0..=255u8 {
+        let vm = VmBuilder::new().with_registers(&[(1, value)]).with_i(0x300).with_program(&[0xF133]).run();
+
+        assert_eq!(&vm.memory[0x300..0x303], &[value / 100, value / 10 % 10, value % 10], "{}", value);
+        assert_eq!(vm.i, 0x300);
+    }
+}
+
+#[test]
+fn store_and_load_registers() {
+    let registers: Vec<(u8, u8)> = (0..16).map(|x| (x, 0x10 + x)).collect();
+
+    let vm = VmBuilder::new().with_registers(&registers).with_i(0x300).with_program(&[0xF355]).run();
+    assert_eq!(&vm.memory[0x300..0x305], &[0x10, 0x11, 0x12, 0x13, 0x00]);
+    assert_eq!(vm.i, 0x300);
+
+    let vm = VmBuilder::new()
+        .with_i(0x300)
+        .with_memory(0x300, &[1, 2, 3, 4])
+        .with_quirks(Quirks::cosmac_vip())
+        .with_program(&[0xF265])
+        .run();
+    assert_eq!(&vm.registers[0..4], &[1, 2, 3, 0]);
+    assert_eq!(vm.i, 0x303);
+}
+
+// Positions of the pixels set in any plane
+fn lit_pixels(vm: &VM) -> Vec<(usize, usize)> {
+    let mut pixels: Vec<(usize, usize)> = Vec::new();
+    for x in 0..vm.screen.width() {
+        for y in 0..vm.screen.height() {
+            if vm.screen.pixels[x][y] != 0 {
+                pixels.push((x, y));
+            }
+        }
+    }
+
+    return pixels;
+}
+
+#[test]
+fn scrolls_move_the_screen() {
+    let scrolled = |opcode| {
+        let mut vm = VmBuilder::new().with_program(&[opcode]).build();
+        vm.screen.pixels[10][10] = 1;
+        vm.next().unwrap();
+
+        return lit_pixels(&vm);
+    };
+
+    assert_eq!(scrolled(0x00C3), [(10, 13)]);
+    assert_eq!(scrolled(0x00D2), [(10, 8)]);
+    assert_eq!(scrolled(0x00FB), [(14, 10)]);
+    assert_eq!(scrolled(0x00FC), [(6, 10)]);
+    assert_eq!(scrolled(0x00C0), [(10, 10)]);
+}
+
+#[test]
+fn scrolls_drop_the_pixels_leaving_the_screen() {
+    let mut vm = VmBuilder::new().with_program(&[0x00FB, 0x00CF, 0x00CF, 0x00CF]).build();
+    vm.screen.pixels[61][0] = 1;
+    vm.screen.pixels[0][20] = 1;
+
+    vm.next().unwrap();
+    assert_eq!(lit_pixels(&vm), [(4, 20)]);
+
+    for _ in 0..3 {
+        vm.next().unwrap();
+    }
+    assert!(lit_pixels(&vm).is_empty());
+}
+
+#[test]
+fn scrolls_only_move_the_selected_planes() {
+    let mut vm = VmBuilder::new().with_program(&[0xF201, 0x00C1]).build();
+    vm.screen.pixels[0][0] = 0b11;
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.screen.pixels[0][0], 0b01);
+    assert_eq!(vm.screen.pixels[0][1], 0b10);
+}
+
+#[test]
+fn resolution_switches_clear_the_screen() {
+    let mut vm = VmBuilder::new().with_program(&[0x00FF, 0x00FE]).build();
+    vm.screen.pixels[1][1] = 1;
+
+    vm.next().unwrap();
+    assert!(vm.screen.hires);
+    assert_eq!((vm.screen.width(), vm.screen.height()), (128, 64));
+    assert!(lit_pixels(&vm).is_empty());
+
+    vm.screen.pixels[100][50] = 1;
+    vm.next().unwrap();
+    assert!(!vm.screen.hires);
+    assert_eq!((vm.screen.width(), vm.screen.height()), (64, 32));
+    assert_eq!(vm.screen.pixels[100][50], 0);
+}
+
+#[test]
+fn exit_halts_the_vm() {
+    let mut vm = VmBuilder::new().with_program(&[0x00FD]).build();
+
+    assert_eq!(vm.next(), Ok(StepResult::Halted));
+    assert!(vm.halted);
+    assert_eq!(vm.pc, 0x200);
+    assert_eq!(vm.next(), Ok(StepResult::Halted));
+}
+
+#[test]
+fn drw_with_zero_rows_draws_a_16x16_sprite() {
+    // Corners of a 16x16 square: both bytes of the first and last rows, the edges in between
+    let mut sprite = vec![0xFF, 0xFF];
+    for _ in 0..14 {
+        sprite.extend_from_slice(&[0x80, 0x01]);
+    }
+    sprite.extend_from_slice(&[0xFF, 0xFF]);
+
+    let mut vm = VmBuilder::new()
+        .with_registers(&[(1, 100), (2, 40)])
+        .with_i(0x300)
+        .with_memory(0x300, &sprite)
+        .with_program(&[0x00FF, 0xD120])
+        .build();
+    vm.next().unwrap();
+    vm.next().unwrap();
+
+    let pixels = lit_pixels(&vm);
+    assert_eq!(pixels.len(), 16 * 4 - 4);
+    for corner in [(100, 40), (115, 40), (100, 55), (115, 55)] {
+        assert!(pixels.contains(&corner));
+    }
+    assert!(!pixels.contains(&(101, 41)));
+    assert_eq!(vm.registers[0xF], 0);
+}
+
+#[test]
+fn drw_in_hires_reports_collisions() {
+    let mut vm = VmBuilder::new()
+        .with_quirks(Quirks::schip_modern())
+        .with_registers(&[(1, 120), (2, 60)])
+        .with_i(0x300)
+        .with_memory(0x300, &[0xFF; 32])
+        .with_program(&[0x00FF, 0xD120, 0xD120])
+        .build();
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    // Clipped at the right and bottom edges
+    assert_eq!(lit_pixels(&vm).len(), 8 * 4);
+    assert_eq!(vm.registers[0xF], 0);
+
+    vm.next().unwrap();
+    assert!(lit_pixels(&vm).is_empty());
+    assert_eq!(vm.registers[0xF], 1);
+}
+
+#[test]
+fn register_ranges_are_saved_and_loaded_in_either_order() {
+    let registers: Vec<(u8, u8)> = (0..16).map(|x| (x, 0x10 + x)).collect();
+
+    let vm = VmBuilder::new().with_registers(&registers).with_i(0x300).with_program(&[0x5242]).run();
+    assert_eq!(&vm.memory[0x300..0x304], &[0x12, 0x13, 0x14, 0x00]);
+    assert_eq!(vm.i, 0x300);
+
+    // Descending ranges store VX first
+    let vm = VmBuilder::new().with_registers(&registers).with_i(0x300).with_program(&[0x5422]).run();
+    assert_eq!(&vm.memory[0x300..0x303], &[0x14, 0x13, 0x12]);
+
+    let vm = VmBuilder::new().with_i(0x300).with_memory(0x300, &[7, 8, 9]).with_program(&[0x5353]).run();
+    assert_eq!(&vm.registers[2..7], &[0, 7, 8, 9, 0]);
+
+    let vm = VmBuilder::new().with_i(0x300).with_memory(0x300, &[7, 8, 9]).with_program(&[0x5333]).run();
+    assert_eq!(&vm.registers[2..5], &[0, 7, 0]);
+
+    let vm = VmBuilder::new().with_i(0x300).with_memory(0x300, &[7, 8, 9]).with_program(&[0x5313]).run();
+    assert_eq!(&vm.registers[0..5], &[0, 9, 8, 7, 0]);
+}
+
+#[test]
+fn long_i_load_reads_the_next_word() {
+    let vm = VmBuilder::new().with_program(&[0xF000, 0xBEEF]).run();
+
+    assert_eq!(vm.i, 0xBEEF);
+    assert_eq!(vm.pc, 0x204);
+}
+
+#[test]
+fn planes_select_where_sprites_are_drawn() {
+    // One row for each plane when both are selected
+    let mut vm = VmBuilder::new()
+        .with_i(0x300)
+        .with_memory(0x300, &[0b10000000, 0b11000000])
+        .with_program(&[0xF201, 0xD001, 0xF301, 0xD001, 0xF001, 0xD001])
+        .build();
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.screen.planes, 0b10);
+    assert_eq!((vm.screen.pixels[0][0], vm.screen.pixels[1][0]), (0b10, 0b00));
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!((vm.screen.pixels[0][0], vm.screen.pixels[1][0]), (0b01, 0b10));
+    assert_eq!(vm.registers[0xF], 1);
+
+    // Without planes nothing is drawn
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!((vm.screen.pixels[0][0], vm.screen.pixels[1][0]), (0b01, 0b10));
+    assert_eq!(vm.registers[0xF], 0);
+}
+
+#[test]
+fn cls_only_clears_the_selected_planes() {
+    let mut vm = VmBuilder::new().with_program(&[0xF101, 0x00E0, 0xF301, 0x00E0]).build();
+    vm.screen.pixels[3][3] = 0b11;
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.screen.pixels[3][3], 0b10);
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.screen.pixels[3][3], 0);
+}
+
+#[test]
+fn audio_copies_the_pattern_buffer() {
+    let pattern: Vec<u8> = (0..16).map(|n| n * 17).collect();
+    let vm = VmBuilder::new().with_i(0x300).with_memory(0x300, &pattern).with_program(&[0xF002]).run();
+
+    assert_eq!(vm.audio_pattern.to_vec(), pattern);
+    assert_eq!(vm.pc, 0x202);
+}
+
+#[test]
+fn pitch_is_set_from_a_register() {
+    let vm = VmBuilder::new().with_registers(&[(4, 112)]).with_program(&[0xF43A]).run();
+
+    assert_eq!(vm.pitch, 112);
+}
+
+#[test]
+fn big_font_sprites() {
+    let vm = VmBuilder::new().with_registers(&[(1, 9)]).with_program(&[0xF130]).run();
+
+    assert_eq!(vm.i, 0xA0 + 90);
+    assert_eq!(vm.memory[0xA0..0xAA], BIG_FONT[..10]);
+}
+
+#[test]
+fn flags_store_and_restore_registers() {
+    let registers: Vec<(u8, u8)> = (0..16).map(|x| (x, 0x20 + x)).collect();
+
+    let mut vm = VmBuilder::new().with_registers(&registers).with_program(&[0xF275, 0x6000, 0x6100, 0x6200, 0xF185]).build();
+    for _ in 0..5 {
+        vm.next().unwrap();
+    }
+
+    assert_eq!(&vm.rpl_flags[0..4], &[0x20, 0x21, 0x22, 0]);
+    // Only V0 and V1 are restored
+    assert_eq!(&vm.registers[0..3], &[0x20, 0x21, 0]);
+}
+
+#[test]
+fn wait_for_key_press() {
+    let mut vm = VmBuilder::new().with_program(&[0xF10A]).build();
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x200);
+
+    vm.key_down(0x7);
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x202);
+    assert_eq!(vm.registers[1], 0x7);
+}
+
+#[test]
+fn wait_for_key_release_with_the_quirk() {
+    let mut vm = VmBuilder::new().with_quirks(Quirks::cosmac_vip()).with_program(&[0xF10A]).build();
+
+    vm.next().unwrap();
+    vm.key_down(0x7);
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x200);
+
+    vm.key_up(0x7);
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x202);
+    assert_eq!(vm.registers[1], 0x7);
+}
+
+#[test]
+fn keys_held_before_fx0a_are_ignored() {
+    let mut vm = VmBuilder::new().with_keys(&[0x3]).with_program(&[0xF10A]).build();
+
+    vm.next().unwrap();
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x200);
+
+    vm.key_down(0x4);
+    vm.next().unwrap();
+    assert_eq!(vm.registers[1], 0x4);
+}
+
+#[test]
+fn unknown_opcodes_follow_the_error_policy() {
+    let vm = VmBuilder::new().with_program(&[0x5121]).run();
+    assert_eq!(vm.pc, 0x202);
+    assert_eq!(vm.last_error, Some(VmError::UnknownOpcode { address: 0x200, opcode: 0x5121 }));
+
+    let mut vm = VmBuilder::new().with_program(&[0x5121]).with_error_policy(ErrorPolicy::Halt).build();
+    assert!(vm.next().is_err());
+    assert!(vm.halted);
+    assert_eq!(vm.pc, 0x200);
+}
+
+#[test]
+fn memory_accesses_are_checked_against_the_platform_memory_size() {
+    let builder = |quirks, opcode| {
+        VmBuilder::new()
+            .with_quirks(quirks)
+            .with_i(0xFFE)
+            .with_program(&[opcode])
+            .with_error_policy(ErrorPolicy::Halt)
+            .build()
+    };
+
+    // FX55, FX65, FX33 and DXYN touching three bytes from 0xFFE on a 4K platform
+    for opcode in [0xF255, 0xF265, 0xF033, 0xD003] {
+        let mut vm = builder(Quirks::cosmac_vip(), opcode);
+
+        assert_eq!(vm.next(), Err(VmError::MemoryOutOfRange { address: 0x200, start: 0xFFE, length: 3 }));
+        assert!(vm.halted);
+    }
+
+    for quirks in [Quirks::schip_legacy(), Quirks::schip_modern()] {
+        assert!(builder(quirks, 0xF255).next().is_err());
+    }
+
+    // The last two bytes of a 4K platform are still in range
+    let mut vm = builder(Quirks::cosmac_vip(), 0xF155);
+    vm.next().unwrap();
+    assert_eq!(vm.pc, 0x202);
+
+    // 64K platforms go on past 0xFFF
+    let mut vm = builder(Quirks::xo_chip(), 0xF255);
+    vm.next().unwrap();
+    assert_eq!(vm.memory[0xFFF], 0);
+    assert_eq!(vm.pc, 0x202);
+}
+
+#[test]
+fn watchpoints_stop_on_writes_of_the_same_value() {
+    // FX55 stores V0 = 0 over a zero byte, 6100 loads 0 into V1 which already holds it
+    let mut vm = VmBuilder::new().with_i(0x300).with_program(&[0xF055, 0x6100, 0x6202]).build();
+    let mut debugger = Debugger::new();
+    debugger.watchpoints = vec![Watchpoint::Memory(0x300), Watchpoint::Register(1)];
+
+    debugger.step(&mut vm).unwrap();
+    assert_eq!(debugger.stop_reason.as_deref(), Some("[0x300] 0x00 -> 0x00 at 0x200"));
+
+    debugger.resume();
+    debugger.step(&mut vm).unwrap();
+    assert_eq!(debugger.stop_reason.as_deref(), Some("V1 0x00 -> 0x00 at 0x202"));
+
+    debugger.resume();
+    debugger.step(&mut vm).unwrap();
+    assert!(!debugger.paused);
+}
+
+#[test]
+fn running_past_the_end_of_memory_halts() {
+    // A plain instruction, a skip, a return and F000 NNNN in the last bytes of memory
+    for (pc, opcode) in [(0xFFFE, 0x6001), (0xFFFC, 0x3000), (0xFFFE, 0x00EE), (0xFFFC, 0xF000)] {
+        let mut vm = VmBuilder::new()
+            .with_stack(&[0xFFFE])
+            .with_memory(pc, &[(opcode >> 8) as u8, opcode as u8])
+            .build();
+        vm.pc = pc;
+
+        assert_eq!(vm.next().unwrap(), StepResult::Halted);
+        assert!(vm.halted);
+    }
+}
+
+#[test]
+fn save_state_keeps_deep_stacks_and_the_cycle_count() {
+    let stack: Vec<u16> = (0..300).map(|n| 0x200 + 2 * n).collect();
+    let mut vm = VmBuilder::new().with_stack(&stack).with_program(&[0x6001, 0x6102]).build();
+    vm.next().unwrap();
+
+    let state = vm.save_state();
+    vm.next().unwrap();
+    vm.load_state(&state).unwrap();
+
+    assert_eq!(vm.stack, stack);
+    assert_eq!(vm.cycles, 1);
+    assert_eq!(vm.pc, 0x202);
+}
+
+// Expected registers after an 8XYN opcode. The flag is set after VX, so it wins when X is F.
+fn reference(n: u8, x: usize, y: usize, mut registers: [u8; 16], quirks: Quirks) -> [u8; 16] {
+    let (vx, vy) = (registers[x], registers[y]);
+    let shifted = if quirks.shift { vx } else { vy };
+    let reset = if quirks.vf_reset { Some(0) } else { None };
+
+    let (result, flag) = match n {
+        0x1 => (vx | vy, reset),
+        0x2 => (vx & vy, reset),
+        0x3 => (vx ^ vy, reset),
+        0x4 => ((vx as u16 + vy as u16) as u8, Some((vx as u16 + vy as u16 > 0xFF) as u8)),
+        0x5 => ((vx as i16 - vy as i16) as u8, Some((vx >= vy) as u8)),
+        0x6 => (shifted / 2, Some(shifted % 2)),
+        0x7 => ((vy as i16 - vx as i16) as u8, Some((vy >= vx) as u8)),
+        0xE => ((shifted as u16 * 2) as u8, Some(shifted / 0x80)),
+        _ => unreachable!(),
+    };
+
+    registers[x] = result;
+    if let Some(flag) = flag {
+        registers[0xF] = flag;
+    }
+
+    return registers;
+}
+
+static ARITHMETIC: [u8; 8] = [0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE];
+
+fn execute_8xyn(vm: &mut VM, n: u8, x: usize, y: usize, registers: [u8; 16]) {
+    let opcode = 0x8000 | (x as u16) << 8 | (y as u16) << 4 | n as u16;
+    vm.memory[0x200] = (opcode >> 8) as u8;
+    vm.memory[0x201] = opcode as u8;
+    vm.registers = registers;
+    vm.pc = 0x200;
+
+    vm.next().unwrap();
+}
+
+#[test]
+fn arithmetic_matches_the_reference_for_every_input() {
+    let mut vm = VmBuilder::new().build();
+
+    for quirks in [quirks(false, false), quirks(true, true)] {
+        vm.set_quirks(quirks);
+
+        for n in ARITHMETIC {
+            for vx in 0..=255u8 {
+                for vy in 0..=255u8 {
+                    let mut registers = [0; 16];
+                    registers[1] = vx;
+                    registers[2] = vy;
+                    registers[0xF] = 0xAA;
+
+                    execute_8xyn(&mut vm, n, 1, 2, registers);
+
+                    let expected = reference(n, 1, 2, registers, quirks);
+                    assert_eq!(vm.registers, expected, "812{:X} with V1={:02X} V2={:02X}", n, vx, vy);
+                }
+            }
+        }
+    }
+}
+
+proptest! {
+    // Any pair of registers, including VF and X equal to Y
+    #[test]
+    fn arithmetic_matches_the_reference(
+        n in proptest::sample::select(ARITHMETIC.to_vec()),
+        x in 0..16usize,
+        y in 0..16usize,
+        registers in any::<[u8; 16]>(),
+        shift in any::<bool>(),
+        vf_reset in any::<bool>(),
+    ) {
+        let quirks = quirks(shift, vf_reset);
+        let mut vm = VmBuilder::new().with_quirks(quirks).build();
+
+        execute_8xyn(&mut vm, n, x, y, registers);
+
+        prop_assert_eq!(vm.registers, reference(n, x, y, registers, quirks));
+        prop_assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn bcd_digits_add_up(value in any::<u8>(), i in 0x200u16..0xFFF0) {
+        let vm = VmBuilder::new().with_registers(&[(4, value)]).with_i(i).with_program(&[0xF433]).run();
+        let digits = &vm.memory[i as usize..i as usize + 3];
+
+        prop_assert!(digits.iter().all(|digit| *digit < 10));
+        prop_assert_eq!(digits[0] as u16 * 100 + digits[1] as u16 * 10 + digits[2] as u16, value as u16);
+    }
+}
+
+// Trace output kept in memory so the test can read it back
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        return Ok(bytes.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[test]
+fn trace_keeps_the_last_instructions_until_halt() {
+    let buffer = SharedBuffer::default();
+    let settings = TraceSettings { last: Some(2), ..TraceSettings::default() };
+
+    let mut vm = VmBuilder::new().with_registers(&[(2, 0x20)]).with_program(&[0x6110, 0x6130, 0x8124, 0x00FD]).build();
+    vm.tracer = Some(Tracer::new(Box::new(buffer.clone()), settings).unwrap());
+
+    for _ in 0..3 {
+        vm.next().unwrap();
+    }
+    assert!(buffer.0.lock().unwrap().is_empty());
+
+    assert_eq!(vm.next(), Ok(StepResult::Halted));
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("       2  0x204  8124"));
+    assert!(lines[0].ends_with("I=0000 V1=30->50"));
+    assert!(lines[1].contains("0x206  00FD"));
+}
+
+#[test]
+fn trace_keeps_the_last_instructions_until_dropped() {
+    let buffer = SharedBuffer::default();
+    let settings = TraceSettings { last: Some(2), ..TraceSettings::default() };
+
+    let mut vm = VmBuilder::new().with_program(&[0x6110, 0x6220, 0x6330]).build();
+    vm.tracer = Some(Tracer::new(Box::new(buffer.clone()), settings).unwrap());
+
+    for _ in 0..3 {
+        vm.next().unwrap();
+    }
+    assert!(buffer.0.lock().unwrap().is_empty());
+
+    // Stopping without a halt, like a closed window or a cycle limit
+    drop(vm);
+    let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("0x202  6220"));
+    assert!(lines[1].contains("0x204  6330"));
+}
@@ -0,0 +1,286 @@
+use std::path::PathBuf;
+
+use clap::{ArgGroup, Args, Parser, Subcommand};
+
+use crate::audio::Waveform;
+use crate::chip8::ErrorPolicy;
+use crate::debugger::{Breakpoint, OpcodePattern, Watchpoint};
+use crate::keymap::Keymap;
+use crate::quirks::{self, Quirks};
+use crate::trace::{self, PcRange, TraceFormat, TraceSettings};
+use crate::tui::{self, Glyphs};
+
+/// CHIP-8, SUPER-CHIP and XO-CHIP interpreter
+#[derive(Parser, Debug)]
+#[command(name = "chipr", version, about)]
+pub struct Cli {
+    /// Configuration file, chipr/config.toml in the user config directory by default
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run a ROM in a window
+    Run(Box<RunArgs>),
+    /// Measure how many instructions per second the interpreter executes
+    Bench(BenchArgs),
+    /// Print the memory image after loading a ROM
+    Dump(MachineArgs),
+    /// Disassemble a ROM
+    Disasm(DisasmArgs),
+    /// Run a ROM without a window and print every executed instruction
+    Trace(TraceArgs),
+    /// Assemble Octo-style source into a ROM
+    Asm(AsmArgs),
+}
+
+// Options shared by every subcommand that loads a ROM into a VM
+#[derive(Args, Debug)]
+pub struct MachineArgs {
+    /// ROM file: raw binary, hex dump (.hex, .txt) or Octo cartridge (.gif)
+    pub rom: PathBuf,
+
+    /// Quirks preset, one of chipr, cosmac-vip, schip-legacy, schip-modern or xo-chip [default: chipr]
+    #[arg(short, long, value_parser = parse_quirks)]
+    pub quirks: Option<Quirks>,
+
+    /// Address the ROM is loaded at and started from, e.g. 0x600 for ETI-660 ROMs [default: 0x200]
+    #[arg(short, long, value_parser = parse_address)]
+    pub load_address: Option<u16>,
+
+    /// What to do on unknown opcodes, machine calls, stack underflows and out-of-range accesses
+    #[arg(short = 'e', long, default_value = "ignore", value_parser = parse_error_policy)]
+    pub on_error: ErrorPolicy,
+}
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("windowless").args(["wav", "headless"])))]
+#[command(group(ArgGroup::new("trace-options").args(["trace_format", "trace_pc", "trace_op", "trace_last"]).multiple(true).requires("trace")))]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub machine: MachineArgs,
+
+    /// Instructions executed per second [default: 450]
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..=1_000_000))]
+    pub rate: Option<u32>,
+
+    /// Instructions executed per frame, derived from the rate by default
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..=100_000))]
+    pub cycles_per_frame: Option<u32>,
+
+    /// Run the VM on its own thread instead of locking it to the frame rate
+    #[arg(long)]
+    pub threaded: bool,
+
+    /// Buzzer waveform, square or sine
+    #[arg(short, long, default_value = "square", value_parser = parse_waveform)]
+    pub wave: Waveform,
+
+    /// Buzzer frequency in Hz, between 20 and 20000
+    #[arg(short, long, default_value_t = 440.0, value_parser = parse_frequency)]
+    pub freq: f32,
+
+    /// Buzzer volume between 0 and 1
+    #[arg(short, long, default_value_t = 0.25, value_parser = parse_volume)]
+    pub volume: f32,
+
+    /// Keyboard layout (row, qwerty-4x4, azerty-4x4) or 16 comma-separated key names for keys 0 to F [default: row]
+    #[arg(short, long, value_parser = Keymap::parse)]
+    pub keymap: Option<Keymap>,
+
+    /// Start with the sound muted
+    #[arg(short, long)]
+    pub mute: bool,
+
+    /// Save state to restore once the ROM is loaded
+    #[arg(short, long)]
+    pub state: Option<PathBuf>,
+
+    /// Seconds of history kept for rewinding, 0 disables it
+    #[arg(long, default_value_t = 30)]
+    pub rewind: u32,
+
+    /// Show the debugger panel
+    #[arg(short, long)]
+    pub debug: bool,
+
+    /// Breakpoints, as addresses (0x2A4) or opcode patterns (op:8XY6)
+    #[arg(short, long = "break", value_delimiter = ',', value_parser = parse_breakpoint)]
+    pub breakpoints: Vec<Breakpoint>,
+
+    /// Watchpoints, as registers (V3) or memory addresses (mem:0x300)
+    #[arg(long = "watch", value_delimiter = ',', value_parser = parse_watchpoint)]
+    pub watchpoints: Vec<Watchpoint>,
+
+    /// Run in the terminal instead of a window
+    #[arg(long, conflicts_with_all = ["threaded", "debug", "breakpoints", "watchpoints", "wav"])]
+    pub tui: bool,
+
+    /// Characters used to draw pixels in the terminal, half-block or braille
+    #[arg(long, default_value = "half-block", value_parser = parse_glyphs, requires = "tui")]
+    pub glyphs: Glyphs,
+
+    /// Run without a window and record the buzzer to this WAV file
+    #[arg(long)]
+    pub wav: Option<PathBuf>,
+
+    /// Run without a window and print the final registers, memory hash and screen as JSON
+    #[arg(long, conflicts_with_all = ["threaded", "debug", "breakpoints", "watchpoints", "tui"])]
+    pub headless: bool,
+
+    /// Frames to run without a window
+    #[arg(long, default_value_t = 600, requires = "windowless")]
+    pub frames: u64,
+
+    /// Instructions to run headless, instead of a number of frames
+    #[arg(short = 'n', long, requires = "headless", conflicts_with = "frames")]
+    pub cycles: Option<u64>,
+
+    /// Input script for headless runs, with lines such as "30 press 5" (frame, down/up/press, key)
+    #[arg(short, long, requires = "headless")]
+    pub input: Option<PathBuf>,
+
+    /// Write the final screen of a headless run to this file, as PNG if it ends in .png or ASCII art otherwise
+    #[arg(long, requires = "headless")]
+    pub screenshot: Option<PathBuf>,
+
+    /// Log every executed instruction to this file
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub trace_options: TraceOptions,
+}
+
+// Which instructions are traced and how
+#[derive(Args, Debug)]
+pub struct TraceOptions {
+    /// Trace format, text or binary [default: text]
+    #[arg(long, value_parser = parse_trace_format)]
+    pub trace_format: Option<TraceFormat>,
+
+    /// Only trace instructions at these addresses, as a range such as 0x200-0x2FF
+    #[arg(long, value_parser = parse_pc_range)]
+    pub trace_pc: Option<PcRange>,
+
+    /// Only trace instructions matching these opcode patterns, such as 8XYN or DXYN
+    #[arg(long, value_delimiter = ',', value_parser = parse_opcode_pattern)]
+    pub trace_op: Vec<OpcodePattern>,
+
+    /// Only keep the last N traced instructions, written when the VM halts or the run ends
+    #[arg(long, value_name = "N")]
+    pub trace_last: Option<usize>,
+}
+
+impl TraceOptions {
+    pub fn settings(&self) -> TraceSettings {
+        TraceSettings {
+            format: self.trace_format.unwrap_or_default(),
+            pc_range: self.trace_pc,
+            opcodes: self.trace_op.clone(),
+            last: self.trace_last,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    pub machine: MachineArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct DisasmArgs {
+    /// ROM file: raw binary, hex dump (.hex, .txt) or Octo cartridge (.gif)
+    pub rom: PathBuf,
+
+    /// Address the ROM is loaded at
+    #[arg(short, long, default_value = "0x200", value_parser = parse_address)]
+    pub load_address: u16,
+}
+
+#[derive(Args, Debug)]
+pub struct TraceArgs {
+    #[command(flatten)]
+    pub machine: MachineArgs,
+
+    /// Number of instructions to execute
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    pub cycles: u64,
+
+    /// Instructions executed per second, used to tick the timers [default: 450]
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..=1_000_000))]
+    pub rate: Option<u32>,
+
+    #[command(flatten)]
+    pub trace_options: TraceOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct AsmArgs {
+    /// Source file
+    pub input: PathBuf,
+    /// ROM file to write
+    pub output: PathBuf,
+}
+
+fn parse_quirks(name: &str) -> Result<Quirks, String> {
+    Quirks::from_name(name).ok_or_else(|| format!("expected one of: {}", quirks::PRESET_NAMES.join(", ")))
+}
+
+fn parse_error_policy(name: &str) -> Result<ErrorPolicy, String> {
+    ErrorPolicy::from_name(name).ok_or_else(|| String::from("expected one of: ignore, log, halt"))
+}
+
+fn parse_waveform(name: &str) -> Result<Waveform, String> {
+    Waveform::from_name(name).ok_or_else(|| String::from("expected one of: square, sine"))
+}
+
+fn parse_glyphs(name: &str) -> Result<Glyphs, String> {
+    Glyphs::from_name(name).ok_or_else(|| format!("expected one of: {}", tui::GLYPH_NAMES.join(", ")))
+}
+
+fn parse_trace_format(name: &str) -> Result<TraceFormat, String> {
+    TraceFormat::from_name(name).ok_or_else(|| format!("expected one of: {}", trace::TRACE_FORMAT_NAMES.join(", ")))
+}
+
+fn parse_pc_range(text: &str) -> Result<PcRange, String> {
+    PcRange::parse(text).ok_or_else(|| String::from("expected an address range such as 0x200-0x2FF"))
+}
+
+fn parse_opcode_pattern(text: &str) -> Result<OpcodePattern, String> {
+    OpcodePattern::parse(text).ok_or_else(|| String::from("expected 4 hex digits with wildcards, such as 8XYN"))
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+
+    u16::from_str_radix(digits, 16).map_err(|_| String::from("expected a hex address such as 0x200"))
+}
+
+// Audible tones that stay below the Nyquist frequency of the 44.1 kHz output
+fn parse_frequency(text: &str) -> Result<f32, String> {
+    match text.parse::<f32>() {
+        Ok(frequency) if (20.0..=20_000.0).contains(&frequency) => Ok(frequency),
+        _ => Err(String::from("expected a frequency between 20 and 20000 Hz")),
+    }
+}
+
+fn parse_volume(text: &str) -> Result<f32, String> {
+    match text.parse::<f32>() {
+        Ok(volume) if (0.0..=1.0).contains(&volume) => Ok(volume),
+        _ => Err(String::from("expected a number between 0 and 1")),
+    }
+}
+
+fn parse_breakpoint(text: &str) -> Result<Breakpoint, String> {
+    Breakpoint::parse(text).ok_or_else(|| String::from("expected an address (0x2A4) or an opcode pattern (op:8XY6)"))
+}
+
+fn parse_watchpoint(text: &str) -> Result<Watchpoint, String> {
+    Watchpoint::parse(text).ok_or_else(|| String::from("expected a register (V3) or a memory address (mem:0x300)"))
+}
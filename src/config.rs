@@ -0,0 +1,450 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::quirks::Quirks;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "Could not read {}: {}", path.display(), error),
+            ConfigError::Parse(path, error) => write!(f, "Could not parse {}: {}", path.display(), error),
+            ConfigError::Invalid(reason) => write!(f, "Invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+// Same limits as the --rate and --cycles-per-frame options
+static RATE_RANGE: RangeInclusive<u32> = 1..=1_000_000;
+static CYCLES_PER_FRAME_RANGE: RangeInclusive<u32> = 1..=100_000;
+
+// Settings that can be given as global defaults or for a single ROM. Unset values
+// fall back to the next level: ROM entry, then database, then defaults, then built-ins.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RomConfig {
+    // Only used to make the config file readable
+    pub name: Option<String>,
+    pub rate: Option<u32>,
+    pub cycles_per_frame: Option<u32>,
+    pub quirks: Option<String>,
+    pub load_address: Option<u16>,
+    // Up to 4 "#RRGGBB" colors, indexed by the bitplanes a pixel is set in
+    pub palette: Option<Vec<String>>,
+    pub keymap: Option<KeymapConfig>,
+    // Gamepad button names to a CHIP-8 key (0 to F), a hotkey or "none", over the default bindings
+    pub gamepad: Option<HashMap<String, String>>,
+}
+
+// A layout name, or keyboard key names for CHIP-8 keys 0 to F
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum KeymapConfig {
+    Layout(String),
+    Keys(Vec<String>),
+}
+
+impl RomConfig {
+    // Fill the values unset here with those of `fallback`
+    fn or(self, fallback: RomConfig) -> RomConfig {
+        RomConfig {
+            name: self.name.or(fallback.name),
+            rate: self.rate.or(fallback.rate),
+            cycles_per_frame: self.cycles_per_frame.or(fallback.cycles_per_frame),
+            quirks: self.quirks.or(fallback.quirks),
+            load_address: self.load_address.or(fallback.load_address),
+            palette: self.palette.or(fallback.palette),
+            keymap: self.keymap.or(fallback.keymap),
+            gamepad: self.gamepad.or(fallback.gamepad),
+        }
+    }
+
+    // Reject values the VM can't run with, which the command line would refuse too
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(rate) = self.rate.filter(|rate| !RATE_RANGE.contains(rate)) {
+            return Err(ConfigError::Invalid(format!(
+                "rate {} is not between {} and {}",
+                rate,
+                RATE_RANGE.start(),
+                RATE_RANGE.end()
+            )));
+        }
+
+        if let Some(cycles) = self.cycles_per_frame.filter(|cycles| !CYCLES_PER_FRAME_RANGE.contains(cycles)) {
+            return Err(ConfigError::Invalid(format!(
+                "cycles_per_frame {} is not between {} and {}",
+                cycles,
+                CYCLES_PER_FRAME_RANGE.start(),
+                CYCLES_PER_FRAME_RANGE.end()
+            )));
+        }
+
+        return Ok(());
+    }
+
+    // Database entries are only recommendations, so values that would be rejected
+    // in a config file are dropped instead and fall back to the defaults
+    fn without_invalid_values(mut self) -> RomConfig {
+        if self.rate.is_some_and(|rate| !RATE_RANGE.contains(&rate)) {
+            self.rate = None;
+        }
+        if self.cycles_per_frame.is_some_and(|cycles| !CYCLES_PER_FRAME_RANGE.contains(&cycles)) {
+            self.cycles_per_frame = None;
+        }
+        if self.quirks().is_err() {
+            self.quirks = None;
+        }
+        if self.palette().is_err() {
+            self.palette = None;
+        }
+
+        return self;
+    }
+
+    pub fn quirks(&self) -> Result<Option<Quirks>, ConfigError> {
+        match &self.quirks {
+            Some(name) => match Quirks::from_name(name) {
+                Some(quirks) => Ok(Some(quirks)),
+                None => Err(ConfigError::Invalid(format!("unknown quirks preset '{}'", name))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn palette(&self) -> Result<Option<Vec<[u8; 3]>>, ConfigError> {
+        let palette = match &self.palette {
+            Some(palette) => palette,
+            None => return Ok(None),
+        };
+
+        if palette.is_empty() || palette.len() > 4 {
+            return Err(ConfigError::Invalid(String::from("palette must have between 1 and 4 colors")));
+        }
+
+        let mut colors: Vec<[u8; 3]> = Vec::new();
+        for color in palette.iter() {
+            match parse_color(color) {
+                Some(rgb) => colors.push(rgb),
+                None => return Err(ConfigError::Invalid(format!("invalid color '{}', expected #RRGGBB", color))),
+            }
+        }
+
+        return Ok(Some(colors));
+    }
+}
+
+fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let digits = text.strip_prefix('#').unwrap_or(text);
+
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let value = u32::from_str_radix(digits, 16).ok()?;
+
+    return Some([(value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // programs.json of the community CHIP-8 database (https://github.com/chip-8/chip-8-database)
+    pub database: Option<PathBuf>,
+    pub defaults: RomConfig,
+    // Per-ROM overrides, keyed by the SHA-1 of the ROM in lowercase hex
+    pub roms: HashMap<String, RomConfig>,
+    // Database entries by SHA-1, read the first time a ROM is looked up
+    #[serde(skip)]
+    database_roms: OnceCell<HashMap<String, RomConfig>>,
+}
+
+impl Config {
+    // Read a TOML file, or a JSON one when the extension is .json
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Io(path.to_path_buf(), error))?;
+
+        let result = if path.extension().is_some_and(|extension| extension == "json") {
+            serde_json::from_str(&text).map_err(|error| error.to_string())
+        } else {
+            toml::from_str(&text).map_err(|error| error.to_string())
+        };
+
+        let config: Config = result.map_err(|error| ConfigError::Parse(path.to_path_buf(), error))?;
+
+        config.defaults.validate()?;
+        for rom in config.roms.values() {
+            rom.validate()?;
+        }
+
+        return Ok(config);
+    }
+
+    // The config file in the user's config directory, if there is one
+    pub fn default_path() -> Option<PathBuf> {
+        let directory = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?
+            .join("chipr");
+
+        return ["config.toml", "config.json"]
+            .iter()
+            .map(|name| directory.join(name))
+            .find(|path| path.is_file());
+    }
+
+    // Settings for the ROM with the given SHA-1
+    pub fn for_rom(&self, sha1: &str) -> Result<RomConfig, ConfigError> {
+        let database = match self.database_roms()? {
+            Some(roms) => roms.get(sha1).cloned().unwrap_or_default(),
+            None => RomConfig::default(),
+        };
+        let rom = self.roms.get(sha1).cloned().unwrap_or_default();
+
+        return Ok(rom.or(database).or(self.defaults.clone()));
+    }
+
+    fn database_roms(&self) -> Result<Option<&HashMap<String, RomConfig>>, ConfigError> {
+        let path = match &self.database {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        if let Some(roms) = self.database_roms.get() {
+            return Ok(Some(roms));
+        }
+
+        let roms = load_database(path)?;
+
+        return Ok(Some(self.database_roms.get_or_init(|| roms)));
+    }
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    Sha1::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Quirks preset closest to a chip-8-database platform id
+fn preset_of_platform(platform: &str) -> Option<&'static str> {
+    match platform {
+        "originalChip8" | "hybridVIP" => Some("cosmac-vip"),
+        "chip48" | "superchip1" => Some("schip-legacy"),
+        "superchip" => Some("schip-modern"),
+        "xochip" => Some("xo-chip"),
+        _ => None,
+    }
+}
+
+// Recommended settings for every ROM of the community database, which lists programs
+// with their known ROM files keyed by SHA-1
+fn load_database(path: &Path) -> Result<HashMap<String, RomConfig>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|error| ConfigError::Io(path.to_path_buf(), error))?;
+    let programs: serde_json::Value =
+        serde_json::from_str(&text).map_err(|error| ConfigError::Parse(path.to_path_buf(), error.to_string()))?;
+
+    let mut roms: HashMap<String, RomConfig> = HashMap::new();
+
+    for program in programs.as_array().into_iter().flatten() {
+        for (sha1, rom) in program["roms"].as_object().into_iter().flatten() {
+            // The tick rate is the number of instructions per frame
+            let tickrate = rom["tickrate"]
+                .as_u64()
+                .map(|tickrate| tickrate.min(u32::MAX as u64) as u32);
+            let quirks = rom["platforms"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|platform| platform.as_str().and_then(preset_of_platform))
+                .next();
+            let palette = rom["colors"]["pixels"].as_array().map(|pixels| {
+                pixels
+                    .iter()
+                    .filter_map(|color| color.as_str().map(String::from))
+                    .take(4)
+                    .collect::<Vec<String>>()
+            });
+
+            // The first program listing a ROM wins, as when searching the list in order
+            let config = RomConfig {
+                name: program["title"].as_str().map(String::from),
+                rate: tickrate.map(|tickrate| tickrate.saturating_mul(60)),
+                cycles_per_frame: tickrate,
+                quirks: quirks.map(String::from),
+                load_address: None,
+                palette,
+                keymap: None,
+                gamepad: None,
+            };
+            roms.entry(sha1.clone()).or_insert_with(|| config.without_invalid_values());
+        }
+    }
+
+    return Ok(roms);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    // A fresh file for one test, in a directory shared by the config tests
+    fn write_file(name: &str, contents: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("chipr-config-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let path = directory.join(name);
+        fs::write(&path, contents).unwrap();
+
+        return path;
+    }
+
+    fn invalid_reason(result: Result<Config, ConfigError>) -> String {
+        match result {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("expected an invalid configuration, got {:?}", other),
+        }
+    }
+
+    const DATABASE: &str = r##"[
+        {
+            "title": "Fast game",
+            "roms": {
+                "aaaa": { "tickrate": 20, "platforms": ["superchip"], "colors": { "pixels": ["#102030", "#405060"] } }
+            }
+        },
+        {
+            "title": "Broken entry",
+            "roms": {
+                "bbbb": { "tickrate": 0, "platforms": ["xochip"], "colors": { "pixels": [] } },
+                "aaaa": { "tickrate": 30 }
+            }
+        }
+    ]"##;
+
+    #[test]
+    fn roms_are_keyed_by_lowercase_sha1() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn rom_entries_override_the_database_which_overrides_the_defaults() {
+        let config = Config {
+            database: Some(write_file("precedence.json", DATABASE)),
+            defaults: RomConfig {
+                rate: Some(500),
+                cycles_per_frame: Some(8),
+                quirks: Some(String::from("cosmac-vip")),
+                palette: Some(vec![String::from("#000000")]),
+                ..RomConfig::default()
+            },
+            roms: HashMap::from([(
+                String::from("aaaa"),
+                RomConfig { rate: Some(900), ..RomConfig::default() },
+            )]),
+            ..Config::default()
+        };
+
+        let rom = config.for_rom("aaaa").unwrap();
+        assert_eq!(rom.rate, Some(900));
+        assert_eq!(rom.cycles_per_frame, Some(20));
+        assert_eq!(rom.quirks.as_deref(), Some("schip-modern"));
+        assert_eq!(rom.palette().unwrap(), Some(vec![[0x10, 0x20, 0x30], [0x40, 0x50, 0x60]]));
+        assert_eq!(rom.name.as_deref(), Some("Fast game"));
+
+        // Unknown ROMs only get the defaults
+        assert_eq!(config.for_rom("cccc").unwrap(), config.defaults);
+    }
+
+    #[test]
+    fn invalid_database_values_are_skipped() {
+        let config = Config {
+            database: Some(write_file("invalid.json", DATABASE)),
+            defaults: RomConfig { rate: Some(500), ..RomConfig::default() },
+            ..Config::default()
+        };
+
+        // A tickrate of 0 and an empty palette fall back, the platform is still used
+        let rom = config.for_rom("bbbb").unwrap();
+        assert_eq!(rom.rate, Some(500));
+        assert_eq!(rom.cycles_per_frame, None);
+        assert_eq!(rom.palette, None);
+        assert_eq!(rom.quirks.as_deref(), Some("xo-chip"));
+    }
+
+    #[test]
+    fn database_is_read_once() {
+        let path = write_file("once.json", DATABASE);
+        let config = Config { database: Some(path.clone()), ..Config::default() };
+
+        assert_eq!(config.for_rom("aaaa").unwrap().cycles_per_frame, Some(20));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.for_rom("aaaa").unwrap().cycles_per_frame, Some(20));
+    }
+
+    #[test]
+    fn missing_database_is_an_error() {
+        let config = Config { database: Some(PathBuf::from("/nonexistent/programs.json")), ..Config::default() };
+
+        assert!(matches!(config.for_rom("aaaa"), Err(ConfigError::Io(..))));
+    }
+
+    #[test]
+    fn config_files_are_read_as_toml_or_json() {
+        let toml = write_file("config.toml", "[defaults]\nrate = 700\n\n[roms.aaaa]\nquirks = \"xo-chip\"\n");
+        let config = Config::load(&toml).unwrap();
+        assert_eq!(config.defaults.rate, Some(700));
+        assert_eq!(config.roms["aaaa"].quirks.as_deref(), Some("xo-chip"));
+
+        let json = write_file("config.json", r#"{ "defaults": { "cycles_per_frame": 12 }, "roms": { "aaaa": { "load_address": 1536 } } }"#);
+        let config = Config::load(&json).unwrap();
+        assert_eq!(config.defaults.cycles_per_frame, Some(12));
+        assert_eq!(config.roms["aaaa"].load_address, Some(0x600));
+
+        let unknown = write_file("unknown.toml", "[defaults]\nspeed = 700\n");
+        assert!(matches!(Config::load(&unknown), Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn out_of_range_values_in_config_files_are_errors() {
+        let path = write_file("zero-rate.toml", "[roms.aaaa]\nrate = 0\n");
+        assert_eq!(invalid_reason(Config::load(&path)), "rate 0 is not between 1 and 1000000");
+
+        let path = write_file("zero-cycles.toml", "[defaults]\ncycles_per_frame = 0\n");
+        assert_eq!(invalid_reason(Config::load(&path)), "cycles_per_frame 0 is not between 1 and 100000");
+
+        let path = write_file("fast.toml", "[defaults]\nrate = 1000001\n");
+        assert!(Config::load(&path).is_err());
+    }
+
+    #[test]
+    fn quirks_and_palettes_are_checked_when_used() {
+        let rom = RomConfig {
+            quirks: Some(String::from("nes")),
+            palette: Some(vec![String::from("#12345")]),
+            ..RomConfig::default()
+        };
+
+        assert!(matches!(rom.quirks(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(rom.palette(), Err(ConfigError::Invalid(_))));
+        assert!(matches!(RomConfig { palette: Some(Vec::new()), ..rom }.palette(), Err(ConfigError::Invalid(_))));
+    }
+}
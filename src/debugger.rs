@@ -0,0 +1,216 @@
+use crate::chip8::{Location, StepResult, TimerMode, VmError, VM};
+
+// Matches instructions where `instruction & mask == value`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpcodePattern {
+    pub mask: u16,
+    pub value: u16,
+}
+
+impl OpcodePattern {
+    // Parse a pattern such as "8XY6", where any character that isn't a hex digit
+    // is a wildcard nibble
+    pub fn parse(pattern: &str) -> Option<OpcodePattern> {
+        if pattern.chars().count() != 4 {
+            return None;
+        }
+
+        let mut mask: u16 = 0;
+        let mut value: u16 = 0;
+
+        for c in pattern.chars() {
+            mask <<= 4;
+            value <<= 4;
+
+            if let Some(digit) = c.to_digit(16) {
+                mask |= 0xF;
+                value |= digit as u16;
+            }
+        }
+
+        return Some(OpcodePattern { mask, value });
+    }
+
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+    Address(u16),
+    Opcode(OpcodePattern),
+}
+
+impl Breakpoint {
+    // Parse "0x2A4" as an address, or "op:8XY6" as an opcode pattern
+    pub fn parse(text: &str) -> Option<Breakpoint> {
+        if let Some(pattern) = text.strip_prefix("op:") {
+            return OpcodePattern::parse(pattern).map(Breakpoint::Opcode);
+        }
+
+        return parse_address(text).map(Breakpoint::Address);
+    }
+
+    fn matches(&self, vm: &VM) -> bool {
+        match self {
+            Breakpoint::Address(address) => vm.pc == *address,
+            Breakpoint::Opcode(pattern) => pattern.matches(vm.get_instruction()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Watchpoint {
+    Memory(u16),
+    Register(u8),
+}
+
+impl Watchpoint {
+    // Parse "V3" as a register or "mem:0x300" as a memory address
+    pub fn parse(text: &str) -> Option<Watchpoint> {
+        if let Some(address) = text.strip_prefix("mem:") {
+            return parse_address(address).map(Watchpoint::Memory);
+        }
+
+        let register = text.strip_prefix('V').or_else(|| text.strip_prefix('v'))?;
+        match u8::from_str_radix(register, 16) {
+            Ok(register) if register < 16 => Some(Watchpoint::Register(register)),
+            _ => None,
+        }
+    }
+
+    fn location(&self) -> Location {
+        match self {
+            Watchpoint::Memory(address) => Location::Memory(*address),
+            Watchpoint::Register(register) => Location::Register(*register),
+        }
+    }
+
+    fn read(&self, vm: &VM) -> u8 {
+        match self {
+            Watchpoint::Memory(address) => vm.memory[*address as usize],
+            Watchpoint::Register(register) => vm.registers[*register as usize],
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Watchpoint::Memory(address) => format!("[{:#05X}]", address),
+            Watchpoint::Register(register) => format!("V{:X}", register),
+        }
+    }
+}
+
+pub fn parse_address(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+
+    return u16::from_str_radix(digits, 16).ok();
+}
+
+pub struct Debugger {
+    pub paused: bool,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    // Why the VM was last paused
+    pub stop_reason: Option<String>,
+    // Set when resuming so that execution doesn't stop again on the breakpoint it paused at
+    skip_breakpoint: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            paused: false,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            stop_reason: None,
+            skip_breakpoint: false,
+        }
+    }
+
+    pub fn pause(&mut self, reason: String) {
+        self.paused = true;
+        self.stop_reason = Some(reason);
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.stop_reason = None;
+        self.skip_breakpoint = true;
+    }
+
+    pub fn toggle_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if let Some(index) = self.breakpoints.iter().position(|b| *b == breakpoint) {
+            self.breakpoints.remove(index);
+        } else {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    // Execute a single instruction like `VM::next`, pausing if it writes to a watched
+    // location, even with the value it already held, or stops the VM
+    pub fn step(&mut self, vm: &mut VM) -> Result<StepResult, VmError> {
+        let pc = vm.pc;
+
+        self.skip_breakpoint = false;
+        vm.record_writes = !self.watchpoints.is_empty();
+        let result = vm.next();
+
+        let hit = vm.writes.iter().find_map(|(location, old_value)| {
+            let watchpoint = self.watchpoints.iter().find(|w| w.location() == *location)?;
+            Some(format!(
+                "{} {:#04X} -> {:#04X} at {:#05X}",
+                watchpoint.name(), old_value, watchpoint.read(vm), pc
+            ))
+        });
+
+        if let Some(reason) = hit {
+            self.pause(reason);
+        }
+
+        match result {
+            Ok(StepResult::Halted) => self.pause(String::from("Halted")),
+            Err(error) => self.pause(error.to_string()),
+            Ok(StepResult::Continue) => {}
+        }
+
+        return result;
+    }
+
+    // Run a frame like `VM::run_frame`, stopping early on breakpoints and watchpoints.
+    // Timers only tick once the whole frame has been executed.
+    pub fn run_frame(&mut self, vm: &mut VM, cycles_per_frame: u32) {
+        for _ in 0..cycles_per_frame {
+            if self.paused {
+                return;
+            }
+
+            // A waiting FX0A already stopped at its breakpoint when it started
+            if !self.skip_breakpoint && vm.key_wait.is_none() {
+                if let Some(breakpoint) = self.breakpoints.iter().find(|b| b.matches(vm)) {
+                    let reason = match breakpoint {
+                        Breakpoint::Address(address) => format!("Breakpoint at {:#05X}", address),
+                        Breakpoint::Opcode(_) => format!("Opcode {:#06X} at {:#05X}", vm.get_instruction(), vm.pc),
+                    };
+                    self.pause(reason);
+                    return;
+                }
+            }
+
+            if self.step(vm) != Ok(StepResult::Continue) {
+                return;
+            }
+        }
+
+        if vm.timer_mode == TimerMode::External {
+            vm.tick_timers();
+        }
+    }
+}
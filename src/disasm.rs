@@ -0,0 +1,210 @@
+use std::collections::BTreeSet;
+
+use crate::chip8::merge_bytes;
+use crate::instruction::*;
+
+pub struct Disassembler<'a> {
+    rom: &'a [u8],
+    origin: u16,
+    // Bytes reached by following the control flow from the entry point
+    code: Vec<bool>,
+    labels: BTreeSet<u16>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(rom: &'a [u8], origin: u16) -> Self {
+        let mut disassembler = Self {
+            rom,
+            origin,
+            code: vec![false; rom.len()],
+            labels: BTreeSet::new(),
+        };
+
+        disassembler.trace(origin);
+
+        // Targets outside the ROM or inside another instruction get no label line,
+        // so they are printed as plain addresses
+        let starts = disassembler.line_starts();
+        disassembler.labels.retain(|address| starts.contains(address));
+
+        return disassembler;
+    }
+
+    fn word_at(&self, address: u16) -> Option<u16> {
+        let offset = address.checked_sub(self.origin)? as usize;
+
+        match (self.rom.get(offset), self.rom.get(offset + 1)) {
+            (Some(b1), Some(b2)) => Some(merge_bytes(*b1, *b2)),
+            _ => None,
+        }
+    }
+
+    fn decode_at(&self, address: u16) -> Option<Instruction> {
+        let instruction = decode(self.word_at(address)?).ok()?;
+
+        // The address word of F000 NNNN must be part of the ROM too
+        if instruction.size() == 4 {
+            self.word_at(address.wrapping_add(2))?;
+        }
+
+        return Some(instruction);
+    }
+
+    fn is_code(&self, address: u16) -> bool {
+        address
+            .checked_sub(self.origin)
+            .and_then(|offset| self.code.get(offset as usize))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    // Heuristically separate code from data by following every branch from the entry point.
+    // Anything never reached, like sprites or the targets of BNNN jump tables, is treated as data.
+    fn trace(&mut self, entry: u16) {
+        let mut pending = vec![entry];
+
+        while let Some(address) = pending.pop() {
+            if self.is_code(address) {
+                continue;
+            }
+
+            let instruction = match self.decode_at(address) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+
+            let offset = (address - self.origin) as usize;
+            for byte in offset..offset + instruction.size() as usize {
+                self.code[byte] = true;
+            }
+
+            let next = address.wrapping_add(instruction.size());
+
+            match instruction {
+                Instruction::Jp(target) => {
+                    self.labels.insert(target);
+                    pending.push(target);
+                }
+                Instruction::Call(target) => {
+                    self.labels.insert(target);
+                    pending.push(target);
+                    pending.push(next);
+                }
+                Instruction::Ret | Instruction::Exit | Instruction::JpV0(_) => {}
+                Instruction::SeImm(..)
+                | Instruction::SneImm(..)
+                | Instruction::SeReg(..)
+                | Instruction::SneReg(..)
+                | Instruction::Skp(_)
+                | Instruction::Sknp(_) => {
+                    pending.push(next);
+                    let skipped_size = self.decode_at(next).map_or(2, |skipped| skipped.size());
+                    pending.push(next.wrapping_add(skipped_size));
+                }
+                _ => pending.push(next),
+            }
+        }
+    }
+
+    // Addresses where `lines` starts a line
+    fn line_starts(&self) -> BTreeSet<u16> {
+        let mut starts: BTreeSet<u16> = BTreeSet::new();
+        let mut address = self.origin;
+        let end = self.origin as usize + self.rom.len();
+
+        while (address as usize) < end {
+            starts.insert(address);
+
+            let size = if self.is_code(address) {
+                self.decode_at(address).map_or(1, |instruction| instruction.size())
+            } else {
+                1
+            };
+            address = address.wrapping_add(size);
+
+            if address == 0 {
+                break;
+            }
+        }
+
+        return starts;
+    }
+
+    fn address_name(&self, address: u16) -> String {
+        if self.labels.contains(&address) {
+            format!("L{:03X}", address)
+        } else {
+            format!("{:#05X}", address)
+        }
+    }
+
+    // One line per instruction or data byte, with labels on their own line
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        let mut address = self.origin;
+        let end = self.origin as usize + self.rom.len();
+
+        while (address as usize) < end {
+            if self.labels.contains(&address) {
+                lines.push(format!("L{:03X}:", address));
+            }
+
+            let instruction = if self.is_code(address) {
+                self.decode_at(address)
+            } else {
+                None
+            };
+
+            match instruction {
+                Some(instruction) => {
+                    let word = self.word_at(address).unwrap_or(0);
+                    let mnemonic = match instruction {
+                        Instruction::LdILong => {
+                            let long_address = self.word_at(address.wrapping_add(2)).unwrap_or(0);
+                            format!("LD I, {:#06X}", long_address)
+                        }
+                        _ => instruction.mnemonic_with(&|target| self.address_name(target)),
+                    };
+
+                    lines.push(format!("{:#05X}  {:04X}  {}", address, word, mnemonic));
+                    address = address.wrapping_add(instruction.size());
+                }
+                None => {
+                    let byte = self.rom[(address - self.origin) as usize];
+                    let pixels: String = (0..8)
+                        .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+                        .collect();
+
+                    lines.push(format!("{:#05X}  {:02X}    DB {:#04X}  ; {}", address, byte, byte, pixels));
+                    address = address.wrapping_add(1);
+                }
+            }
+
+            if address == 0 {
+                break;
+            }
+        }
+
+        return lines;
+    }
+}
+
+pub fn disassemble(rom: &[u8], origin: u16) -> String {
+    let mut output = Disassembler::new(rom, origin).lines().join("\n");
+    output.push('\n');
+
+    return output;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_targets_with_a_line_get_a_label() {
+        // CALL 0x204, JP 0x300 past the end of the ROM, RET
+        let output = disassemble(&[0x22, 0x04, 0x13, 0x00, 0x00, 0xEE], 0x200);
+
+        assert_eq!(output, "0x200  2204  CALL L204\n0x202  1300  JP 0x300\nL204:\n0x204  00EE  RET\n");
+    }
+}
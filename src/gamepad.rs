@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use gilrs::{Button, EventType, Gilrs};
+
+pub static BUTTON_NAMES: &[&str] = &[
+    "up", "down", "left", "right", "a", "b", "x", "y", "l1", "r1", "l2", "r2", "l3", "r3", "select", "start", "mode",
+];
+
+// Emulator actions that can be bound to a gamepad button
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    Pause,
+    Reset,
+    SaveState,
+    LoadState,
+}
+
+impl Hotkey {
+    pub fn from_name(name: &str) -> Option<Hotkey> {
+        match name.to_lowercase().as_str() {
+            "pause" => Some(Hotkey::Pause),
+            "reset" => Some(Hotkey::Reset),
+            "save-state" | "save" => Some(Hotkey::SaveState),
+            "load-state" | "load" => Some(Hotkey::LoadState),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Key(u8),
+    Hotkey(Hotkey),
+}
+
+impl Binding {
+    // A CHIP-8 key as a hex digit, or a hotkey name
+    pub fn parse(text: &str) -> Option<Binding> {
+        if text.len() == 1 {
+            return u8::from_str_radix(text, 16).ok().map(Binding::Key);
+        }
+
+        return Hotkey::from_name(text).map(Binding::Hotkey);
+    }
+}
+
+// Bindings from gamepad buttons to CHIP-8 keys and hotkeys, shared by every connected gamepad
+#[derive(Clone, Debug, PartialEq)]
+pub struct GamepadBindings {
+    pub bindings: Vec<(Button, Binding)>,
+}
+
+impl GamepadBindings {
+    // Replace the bindings of the given buttons, "none" unbinds a button
+    pub fn with_overrides(mut self, overrides: &HashMap<String, String>) -> Result<GamepadBindings, String> {
+        for (name, value) in overrides.iter() {
+            let button = parse_button_name(name).ok_or_else(|| {
+                format!("unknown gamepad button '{}', expected one of: {}", name, BUTTON_NAMES.join(", "))
+            })?;
+
+            self.bindings.retain(|(bound, _)| *bound != button);
+
+            if value.eq_ignore_ascii_case("none") {
+                continue;
+            }
+
+            let binding = Binding::parse(value).ok_or_else(|| {
+                format!("invalid binding '{}', expected a key from 0 to F, pause, reset, save-state or load-state", value)
+            })?;
+            self.bindings.push((button, binding));
+        }
+
+        return Ok(self);
+    }
+}
+
+impl Default for GamepadBindings {
+    // The d-pad moves like the 2/4/6/8 keys most games use, A is the usual action key
+    fn default() -> Self {
+        Self {
+            bindings: vec![
+                (Button::DPadUp, Binding::Key(0x2)),
+                (Button::DPadLeft, Binding::Key(0x4)),
+                (Button::DPadRight, Binding::Key(0x6)),
+                (Button::DPadDown, Binding::Key(0x8)),
+                (Button::South, Binding::Key(0x5)),
+                (Button::Start, Binding::Hotkey(Hotkey::Pause)),
+                (Button::Select, Binding::Hotkey(Hotkey::Reset)),
+                (Button::RightTrigger, Binding::Hotkey(Hotkey::SaveState)),
+                (Button::LeftTrigger, Binding::Hotkey(Hotkey::LoadState)),
+            ],
+        }
+    }
+}
+
+// Gamepad button from its name, using the Xbox layout for face buttons
+pub fn parse_button_name(name: &str) -> Option<Button> {
+    let button = match name.to_lowercase().as_str() {
+        "up" => Button::DPadUp,
+        "down" => Button::DPadDown,
+        "left" => Button::DPadLeft,
+        "right" => Button::DPadRight,
+        "a" | "south" => Button::South,
+        "b" | "east" => Button::East,
+        "x" | "west" => Button::West,
+        "y" | "north" => Button::North,
+        "l1" | "lb" => Button::LeftTrigger,
+        "r1" | "rb" => Button::RightTrigger,
+        "l2" | "lt" => Button::LeftTrigger2,
+        "r2" | "rt" => Button::RightTrigger2,
+        "l3" => Button::LeftThumb,
+        "r3" => Button::RightThumb,
+        "select" | "back" => Button::Select,
+        "start" => Button::Start,
+        "mode" | "guide" => Button::Mode,
+        _ => return None,
+    };
+
+    return Some(button);
+}
+
+// Connected gamepads, read through gilrs
+pub struct Gamepads {
+    // None when the platform has no gamepad support, the keyboard still works then
+    gilrs: Option<Gilrs>,
+    bindings: GamepadBindings,
+}
+
+impl Gamepads {
+    pub fn new(bindings: GamepadBindings) -> Self {
+        Self {
+            gilrs: Gilrs::new().ok(),
+            bindings,
+        }
+    }
+
+    // Process pending events, returns the hotkeys pressed since the last poll
+    pub fn poll(&mut self) -> Vec<Hotkey> {
+        let mut hotkeys: Vec<Hotkey> = Vec::new();
+        let Some(gilrs) = self.gilrs.as_mut() else {
+            return hotkeys;
+        };
+
+        while let Some(event) = gilrs.next_event() {
+            if let EventType::ButtonPressed(button, _) = event.event {
+                for (bound, binding) in self.bindings.bindings.iter() {
+                    if let Binding::Hotkey(hotkey) = binding {
+                        if *bound == button {
+                            hotkeys.push(*hotkey);
+                        }
+                    }
+                }
+            }
+        }
+
+        return hotkeys;
+    }
+
+    // CHIP-8 keys held on any connected gamepad, bit K set for key K
+    pub fn key_mask(&self) -> u16 {
+        let mut mask: u16 = 0;
+        let Some(gilrs) = self.gilrs.as_ref() else {
+            return mask;
+        };
+
+        for (_, gamepad) in gilrs.gamepads() {
+            for (button, binding) in self.bindings.bindings.iter() {
+                if let Binding::Key(key) = binding {
+                    if gamepad.is_pressed(*button) {
+                        mask |= 1 << (key & 0xF);
+                    }
+                }
+            }
+        }
+
+        return mask;
+    }
+}
@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::chip8::{Screen, StepResult, TimerMode, VM, VmError};
+use crate::config::sha1_hex;
+
+// Characters for each combination of the two XO-CHIP bitplanes
+static ASCII_PIXELS: [char; 4] = ['.', '#', 'o', '@'];
+
+#[derive(Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid input script at line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ScriptError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScriptEvent {
+    // Frame, or cycle when running for a number of cycles, the event happens at
+    pub time: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// Key presses and releases to feed the VM, one per line as "<time> <down|up|press> <key>".
+// A press is a down followed by an up one frame or cycle later. # starts a comment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputScript {
+    pub events: Vec<ScriptEvent>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, ScriptError> {
+        let mut events: Vec<ScriptEvent> = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: &str| ScriptError { line: index + 1, message: String::from(message) };
+
+            let words: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            if words.len() != 3 {
+                return Err(error("expected <time> <down|up|press> <key>"));
+            }
+
+            let time = words[0].parse::<u64>().map_err(|_| error("time must be a number"))?;
+            let key = match u8::from_str_radix(words[2], 16) {
+                Ok(key) if key < 16 => key,
+                _ => return Err(error("key must be a hex digit from 0 to F")),
+            };
+
+            match words[1] {
+                "down" => events.push(ScriptEvent { time, key, pressed: true }),
+                "up" => events.push(ScriptEvent { time, key, pressed: false }),
+                "press" => {
+                    events.push(ScriptEvent { time, key, pressed: true });
+                    events.push(ScriptEvent { time: time + 1, key, pressed: false });
+                }
+                _ => return Err(error("action must be down, up or press")),
+            }
+        }
+
+        // Events at the same time keep the order they were written in
+        events.sort_by_key(|event| event.time);
+
+        return Ok(InputScript { events });
+    }
+
+    pub fn load(path: &Path) -> Result<InputScript, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+
+        return Ok(InputScript::parse(&text)?);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Duration {
+    Frames(u64),
+    Cycles(u64),
+}
+
+// State of the machine at the end of a headless run
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub frames: u64,
+    pub cycles: u64,
+    pub halted: bool,
+    pub error: Option<String>,
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub stack: Vec<u16>,
+    pub memory_sha1: String,
+    pub screen: Vec<String>,
+}
+
+// Run the VM without a window, feeding it the script's inputs. Errors end the run
+// and are part of the report.
+pub fn run(vm: &mut VM, duration: Duration, cycles_per_frame: u32, script: &InputScript) -> Report {
+    let (steps, by_cycle) = match duration {
+        Duration::Frames(frames) => (frames, false),
+        Duration::Cycles(cycles) => (cycles, true),
+    };
+
+    if by_cycle {
+        vm.set_timer_mode(TimerMode::Instructions);
+    }
+
+    let start_cycles = vm.cycles;
+    let mut frames = 0;
+    let mut error: Option<VmError> = None;
+    let mut events = script.events.iter().peekable();
+
+    for time in 0..steps {
+        while let Some(event) = events.next_if(|event| event.time <= time) {
+            if event.pressed {
+                vm.key_down(event.key);
+            } else {
+                vm.key_up(event.key);
+            }
+        }
+
+        let result = if by_cycle {
+            vm.next()
+        } else {
+            frames += 1;
+            vm.run_frame(cycles_per_frame)
+        };
+
+        match result {
+            Ok(StepResult::Continue) => {}
+            Ok(StepResult::Halted) => break,
+            Err(vm_error) => {
+                error = Some(vm_error);
+                break;
+            }
+        }
+    }
+
+    return Report {
+        frames,
+        cycles: vm.cycles - start_cycles,
+        halted: vm.halted,
+        error: error.map(|error| error.to_string()),
+        pc: vm.pc,
+        i: vm.i,
+        v: vm.registers,
+        delay_timer: vm.delay_timer,
+        sound_timer: vm.sound_timer,
+        stack: vm.stack.clone(),
+        memory_sha1: sha1_hex(&vm.memory),
+        screen: ascii_rows(&vm.screen),
+    };
+}
+
+// One string per row of the current resolution
+pub fn ascii_rows(screen: &Screen) -> Vec<String> {
+    (0..screen.height())
+        .map(|y| {
+            (0..screen.width())
+                .map(|x| ASCII_PIXELS[(screen.pixels[x][y] & 0b11) as usize])
+                .collect()
+        })
+        .collect()
+}
+
+// The screen at its current resolution, one image pixel per CHIP-8 pixel
+pub fn write_png(screen: &Screen, palette: &[[u8; 3]; 4], path: &Path) -> io::Result<()> {
+    let file = io::BufWriter::new(fs::File::create(path)?);
+
+    let mut encoder = png::Encoder::new(file, screen.width() as u32, screen.height() as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut data: Vec<u8> = Vec::with_capacity(screen.width() * screen.height() * 3);
+    for y in 0..screen.height() {
+        for x in 0..screen.width() {
+            data.extend_from_slice(&palette[(screen.pixels[x][y] & 0b11) as usize]);
+        }
+    }
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(io::Error::other)?;
+
+    return Ok(());
+}
+
+// PNG when the path ends in .png, ASCII art otherwise
+pub fn write_screen(screen: &Screen, palette: &[[u8; 3]; 4], path: &Path) -> io::Result<()> {
+    if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("png")) {
+        return write_png(screen, palette, path);
+    }
+
+    let mut text = ascii_rows(screen).join("\n");
+    text.push('\n');
+
+    return fs::write(path, text);
+}
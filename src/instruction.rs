@@ -0,0 +1,361 @@
+use std::error::Error;
+use std::fmt;
+
+// Decoded CHIP-8, SUPER-CHIP and XO-CHIP instructions. Register operands are
+// register indexes, immediate operands are the raw values from the opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Sys(u16),
+    Cls,
+    Ret,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    Low,
+    High,
+    Jp(u16),
+    Call(u16),
+    SeImm(u8, u8),
+    SneImm(u8, u8),
+    SeReg(u8, u8),
+    SaveRange(u8, u8),
+    LoadRange(u8, u8),
+    LdImm(u8, u8),
+    AddImm(u8, u8),
+    LdReg(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    AddReg(u8, u8),
+    Sub(u8, u8),
+    Shr(u8, u8),
+    Subn(u8, u8),
+    Shl(u8, u8),
+    SneReg(u8, u8),
+    LdI(u16),
+    JpV0(u16),
+    Rnd(u8, u8),
+    Drw(u8, u8, u8),
+    Skp(u8),
+    Sknp(u8),
+    // F000 NNNN, the address is stored in the word following the opcode
+    LdILong,
+    Plane(u8),
+    Audio,
+    LdVxDt(u8),
+    LdVxK(u8),
+    LdDtVx(u8),
+    LdStVx(u8),
+    AddI(u8),
+    LdF(u8),
+    LdHf(u8),
+    LdB(u8),
+    Pitch(u8),
+    LdIVx(u8),
+    LdVxI(u8),
+    LdRVx(u8),
+    LdVxR(u8),
+}
+
+fn s_bitmask1(instruction: u16) -> u8 {
+    return ((instruction & 0xF000) >> 12) as u8;
+}
+
+fn s_bitmask2(instruction: u16) -> u8 {
+    return ((instruction & 0x0F00) >> 8) as u8;
+}
+
+fn s_bitmask3(instruction: u16) -> u8 {
+    return ((instruction & 0x00F0) >> 4) as u8;
+}
+
+fn s_bitmask4(instruction: u16) -> u8 {
+    return (instruction & 0x000F) as u8;
+}
+
+fn s_bitmask24(instruction: u16) -> u16 {
+    return instruction & 0x0FFF;
+}
+
+fn s_bitmask34(instruction: u16) -> u8 {
+    return (instruction & 0x00FF) as u8;
+}
+
+// An opcode that no supported platform defines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownOpcode(pub u16);
+
+impl fmt::Display for UnknownOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown opcode {:04X}", self.0)
+    }
+}
+
+impl Error for UnknownOpcode {}
+
+pub fn decode(instruction: u16) -> Result<Instruction, UnknownOpcode> {
+    let x = s_bitmask2(instruction);
+    let y = s_bitmask3(instruction);
+    let n = s_bitmask4(instruction);
+    let nn = s_bitmask34(instruction);
+    let nnn = s_bitmask24(instruction);
+
+    let decoded = match s_bitmask1(instruction) {
+        0x0 => match instruction {
+            0x00E0 => Instruction::Cls,
+            0x00EE => Instruction::Ret,
+            0x00C0..=0x00CF => Instruction::ScrollDown(n),
+            0x00D0..=0x00DF => Instruction::ScrollUp(n),
+            0x00FB => Instruction::ScrollRight,
+            0x00FC => Instruction::ScrollLeft,
+            0x00FD => Instruction::Exit,
+            0x00FE => Instruction::Low,
+            0x00FF => Instruction::High,
+            _ => Instruction::Sys(nnn),
+        },
+        0x1 => Instruction::Jp(nnn),
+        0x2 => Instruction::Call(nnn),
+        0x3 => Instruction::SeImm(x, nn),
+        0x4 => Instruction::SneImm(x, nn),
+        0x5 => match n {
+            0x0 => Instruction::SeReg(x, y),
+            0x2 => Instruction::SaveRange(x, y),
+            0x3 => Instruction::LoadRange(x, y),
+            _ => return Err(UnknownOpcode(instruction)),
+        },
+        0x6 => Instruction::LdImm(x, nn),
+        0x7 => Instruction::AddImm(x, nn),
+        0x8 => match n {
+            0x0 => Instruction::LdReg(x, y),
+            0x1 => Instruction::Or(x, y),
+            0x2 => Instruction::And(x, y),
+            0x3 => Instruction::Xor(x, y),
+            0x4 => Instruction::AddReg(x, y),
+            0x5 => Instruction::Sub(x, y),
+            0x6 => Instruction::Shr(x, y),
+            0x7 => Instruction::Subn(x, y),
+            0xE => Instruction::Shl(x, y),
+            _ => return Err(UnknownOpcode(instruction)),
+        },
+        0x9 => match n {
+            0x0 => Instruction::SneReg(x, y),
+            _ => return Err(UnknownOpcode(instruction)),
+        },
+        0xA => Instruction::LdI(nnn),
+        0xB => Instruction::JpV0(nnn),
+        0xC => Instruction::Rnd(x, nn),
+        0xD => Instruction::Drw(x, y, n),
+        0xE => match nn {
+            0x9E => Instruction::Skp(x),
+            0xA1 => Instruction::Sknp(x),
+            _ => return Err(UnknownOpcode(instruction)),
+        },
+        0xF => match nn {
+            0x00 if x == 0 => Instruction::LdILong,
+            0x01 => Instruction::Plane(x),
+            0x02 if x == 0 => Instruction::Audio,
+            0x07 => Instruction::LdVxDt(x),
+            0x0A => Instruction::LdVxK(x),
+            0x15 => Instruction::LdDtVx(x),
+            0x18 => Instruction::LdStVx(x),
+            0x1E => Instruction::AddI(x),
+            0x29 => Instruction::LdF(x),
+            0x30 => Instruction::LdHf(x),
+            0x33 => Instruction::LdB(x),
+            0x3A => Instruction::Pitch(x),
+            0x55 => Instruction::LdIVx(x),
+            0x65 => Instruction::LdVxI(x),
+            0x75 => Instruction::LdRVx(x),
+            0x85 => Instruction::LdVxR(x),
+            _ => return Err(UnknownOpcode(instruction)),
+        },
+        _ => return Err(UnknownOpcode(instruction)),
+    };
+
+    return Ok(decoded);
+}
+
+impl Instruction {
+    // Size in bytes, including the address word of F000 NNNN
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong => 4,
+            _ => 2,
+        }
+    }
+
+    // Raw opcode, the inverse of `decode`. F000 NNNN only encodes its first word.
+    pub fn encode(&self) -> u16 {
+        let xy = |high: u16, x: u8, y: u8, low: u16| high | (x as u16) << 8 | (y as u16) << 4 | low;
+        let xnn = |high: u16, x: u8, nn: u8| high | (x as u16) << 8 | nn as u16;
+
+        match *self {
+            Instruction::Sys(address) => address & 0x0FFF,
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Jp(address) => 0x1000 | (address & 0x0FFF),
+            Instruction::Call(address) => 0x2000 | (address & 0x0FFF),
+            Instruction::SeImm(x, nn) => xnn(0x3000, x, nn),
+            Instruction::SneImm(x, nn) => xnn(0x4000, x, nn),
+            Instruction::SeReg(x, y) => xy(0x5000, x, y, 0x0),
+            Instruction::SaveRange(x, y) => xy(0x5000, x, y, 0x2),
+            Instruction::LoadRange(x, y) => xy(0x5000, x, y, 0x3),
+            Instruction::LdImm(x, nn) => xnn(0x6000, x, nn),
+            Instruction::AddImm(x, nn) => xnn(0x7000, x, nn),
+            Instruction::LdReg(x, y) => xy(0x8000, x, y, 0x0),
+            Instruction::Or(x, y) => xy(0x8000, x, y, 0x1),
+            Instruction::And(x, y) => xy(0x8000, x, y, 0x2),
+            Instruction::Xor(x, y) => xy(0x8000, x, y, 0x3),
+            Instruction::AddReg(x, y) => xy(0x8000, x, y, 0x4),
+            Instruction::Sub(x, y) => xy(0x8000, x, y, 0x5),
+            Instruction::Shr(x, y) => xy(0x8000, x, y, 0x6),
+            Instruction::Subn(x, y) => xy(0x8000, x, y, 0x7),
+            Instruction::Shl(x, y) => xy(0x8000, x, y, 0xE),
+            Instruction::SneReg(x, y) => xy(0x9000, x, y, 0x0),
+            Instruction::LdI(address) => 0xA000 | (address & 0x0FFF),
+            Instruction::JpV0(address) => 0xB000 | (address & 0x0FFF),
+            Instruction::Rnd(x, nn) => xnn(0xC000, x, nn),
+            Instruction::Drw(x, y, n) => xy(0xD000, x, y, n as u16 & 0xF),
+            Instruction::Skp(x) => xnn(0xE000, x, 0x9E),
+            Instruction::Sknp(x) => xnn(0xE000, x, 0xA1),
+            Instruction::LdILong => 0xF000,
+            Instruction::Plane(n) => xnn(0xF000, n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LdVxDt(x) => xnn(0xF000, x, 0x07),
+            Instruction::LdVxK(x) => xnn(0xF000, x, 0x0A),
+            Instruction::LdDtVx(x) => xnn(0xF000, x, 0x15),
+            Instruction::LdStVx(x) => xnn(0xF000, x, 0x18),
+            Instruction::AddI(x) => xnn(0xF000, x, 0x1E),
+            Instruction::LdF(x) => xnn(0xF000, x, 0x29),
+            Instruction::LdHf(x) => xnn(0xF000, x, 0x30),
+            Instruction::LdB(x) => xnn(0xF000, x, 0x33),
+            Instruction::Pitch(x) => xnn(0xF000, x, 0x3A),
+            Instruction::LdIVx(x) => xnn(0xF000, x, 0x55),
+            Instruction::LdVxI(x) => xnn(0xF000, x, 0x65),
+            Instruction::LdRVx(x) => xnn(0xF000, x, 0x75),
+            Instruction::LdVxR(x) => xnn(0xF000, x, 0x85),
+        }
+    }
+
+    // Cowgod-style mnemonic, with `address_name` formatting jump, call and I targets
+    pub fn mnemonic_with(&self, address_name: &dyn Fn(u16) -> String) -> String {
+        match *self {
+            Instruction::Sys(address) => format!("SYS {}", address_name(address)),
+            Instruction::Cls => String::from("CLS"),
+            Instruction::Ret => String::from("RET"),
+            Instruction::ScrollDown(n) => format!("SCD {}", n),
+            Instruction::ScrollUp(n) => format!("SCU {}", n),
+            Instruction::ScrollRight => String::from("SCR"),
+            Instruction::ScrollLeft => String::from("SCL"),
+            Instruction::Exit => String::from("EXIT"),
+            Instruction::Low => String::from("LOW"),
+            Instruction::High => String::from("HIGH"),
+            Instruction::Jp(address) => format!("JP {}", address_name(address)),
+            Instruction::Call(address) => format!("CALL {}", address_name(address)),
+            Instruction::SeImm(x, nn) => format!("SE V{:X}, {:#04X}", x, nn),
+            Instruction::SneImm(x, nn) => format!("SNE V{:X}, {:#04X}", x, nn),
+            Instruction::SeReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            Instruction::SaveRange(x, y) => format!("SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange(x, y) => format!("LOAD V{:X}, V{:X}", x, y),
+            Instruction::LdImm(x, nn) => format!("LD V{:X}, {:#04X}", x, nn),
+            Instruction::AddImm(x, nn) => format!("ADD V{:X}, {:#04X}", x, nn),
+            Instruction::LdReg(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(address) => format!("LD I, {}", address_name(address)),
+            Instruction::JpV0(address) => format!("JP V0, {:#05X}", address),
+            Instruction::Rnd(x, nn) => format!("RND V{:X}, {:#04X}", x, nn),
+            Instruction::Drw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => format!("SKP V{:X}", x),
+            Instruction::Sknp(x) => format!("SKNP V{:X}", x),
+            Instruction::LdILong => String::from("LD I, LONG"),
+            Instruction::Plane(n) => format!("PLANE {}", n),
+            Instruction::Audio => String::from("AUDIO"),
+            Instruction::LdVxDt(x) => format!("LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => format!("LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => format!("LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => format!("LD ST, V{:X}", x),
+            Instruction::AddI(x) => format!("ADD I, V{:X}", x),
+            Instruction::LdF(x) => format!("LD F, V{:X}", x),
+            Instruction::LdHf(x) => format!("LD HF, V{:X}", x),
+            Instruction::LdB(x) => format!("LD B, V{:X}", x),
+            Instruction::Pitch(x) => format!("PITCH V{:X}", x),
+            Instruction::LdIVx(x) => format!("LD [I], V{:X}", x),
+            Instruction::LdVxI(x) => format!("LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => format!("LD R, V{:X}", x),
+            Instruction::LdVxR(x) => format!("LD V{:X}, R", x),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic_with(&|address| format!("{:#05X}", address)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_is_the_inverse_of_decode_for_every_opcode() {
+        let mut decoded = 0;
+
+        for opcode in 0..=0xFFFF {
+            if let Ok(instruction) = decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{:04X} decoded as {:?}", opcode, instruction);
+                assert_eq!(decode(instruction.encode()), Ok(instruction));
+                decoded += 1;
+            }
+        }
+
+        // Every 0NNN to DXYN opcode except 13 of the 16 5XYN, 7 of the 8XYN and 15 of the 9XYN,
+        // then 2 EXNN and 14 FXNN for each X, plus F000 and F002
+        let unknown_xyn = 0x100 * (13 + 7 + 15);
+        assert_eq!(decoded, 14 * 0x1000 - unknown_xyn + 16 * 2 + 16 * 14 + 2);
+    }
+
+    #[test]
+    fn opcodes_outside_the_instruction_set_are_unknown() {
+        for opcode in [0x5121, 0x512F, 0x8128, 0x812F, 0x9121, 0xE1A2, 0xF100, 0xF102, 0xF1FF] {
+            assert_eq!(decode(opcode), Err(UnknownOpcode(opcode)));
+        }
+    }
+
+    #[test]
+    fn long_i_load_takes_two_words() {
+        assert_eq!(decode(0xF000), Ok(Instruction::LdILong));
+        assert_eq!(Instruction::LdILong.size(), 4);
+        assert_eq!(Instruction::Cls.size(), 2);
+    }
+
+    #[test]
+    fn mnemonics_use_cowgod_syntax() {
+        let mnemonic = |opcode| decode(opcode).unwrap().to_string();
+
+        assert_eq!(mnemonic(0x00E0), "CLS");
+        assert_eq!(mnemonic(0x1234), "JP 0x234");
+        assert_eq!(mnemonic(0x6A12), "LD VA, 0x12");
+        assert_eq!(mnemonic(0xD015), "DRW V0, V1, 5");
+        assert_eq!(mnemonic(0x5232), "SAVE V2, V3");
+        assert_eq!(mnemonic(0xF265), "LD V2, [I]");
+        assert_eq!(mnemonic(0x00C4), "SCD 4");
+    }
+}
@@ -0,0 +1,131 @@
+use macroquad::prelude::KeyCode;
+
+pub static LAYOUT_NAMES: &[&str] = &["row", "qwerty-4x4", "azerty-4x4"];
+
+// The COSMAC VIP hex keypad, as laid out on the physical device
+pub static KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// Bindings from keyboard keys to CHIP-8 keys. A CHIP-8 key can have several keyboard keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    pub bindings: Vec<(KeyCode, u8)>,
+}
+
+impl Keymap {
+    // Historical chipr layout, keys 0 to F along a single row of AZERTY letters
+    pub fn row() -> Keymap {
+        Keymap::from_key_names(&["A", "Z", "E", "R", "T", "Y", "U", "I", "O", "P", "Q", "S", "D", "F", "G", "H"])
+            .unwrap()
+    }
+
+    // The keypad mapped onto the 4x4 block of keys under 1234
+    pub fn qwerty_4x4() -> Keymap {
+        Keymap::from_block(["1234", "QWER", "ASDF", "ZXCV"])
+    }
+
+    pub fn azerty_4x4() -> Keymap {
+        Keymap::from_block(["1234", "AZER", "QSDF", "WXCV"])
+    }
+
+    fn from_block(rows: [&str; 4]) -> Keymap {
+        let mut bindings: Vec<(KeyCode, u8)> = Vec::new();
+
+        for (row, keys) in rows.iter().enumerate() {
+            for (column, name) in keys.chars().enumerate() {
+                let key = parse_key_name(&name.to_string()).unwrap();
+                bindings.push((key, KEYPAD[row][column]));
+            }
+        }
+
+        return Keymap { bindings };
+    }
+
+    pub fn from_name(name: &str) -> Option<Keymap> {
+        match name.to_lowercase().as_str() {
+            "row" | "chipr" => Some(Keymap::row()),
+            "qwerty-4x4" | "qwerty" => Some(Keymap::qwerty_4x4()),
+            "azerty-4x4" | "azerty" => Some(Keymap::azerty_4x4()),
+            _ => None,
+        }
+    }
+
+    // One keyboard key name per CHIP-8 key, from 0 to F
+    pub fn from_key_names<S: AsRef<str>>(names: &[S]) -> Result<Keymap, String> {
+        if names.len() != 16 {
+            return Err(format!("expected 16 key names for CHIP-8 keys 0 to F, found {}", names.len()));
+        }
+
+        let mut bindings: Vec<(KeyCode, u8)> = Vec::new();
+        for (byte, name) in names.iter().enumerate() {
+            match parse_key_name(name.as_ref()) {
+                Some(key) => bindings.push((key, byte as u8)),
+                None => return Err(format!("unknown key name '{}'", name.as_ref())),
+            }
+        }
+
+        return Ok(Keymap { bindings });
+    }
+
+    // Either a layout name or a comma-separated list of 16 key names
+    pub fn parse(text: &str) -> Result<Keymap, String> {
+        if text.contains(',') {
+            let names: Vec<&str> = text.split(',').map(|name| name.trim()).collect();
+            return Keymap::from_key_names(&names);
+        }
+
+        return Keymap::from_name(text)
+            .ok_or_else(|| format!("unknown layout '{}', expected one of: {}", text, LAYOUT_NAMES.join(", ")));
+    }
+
+    pub fn keys_for(&self, byte: u8) -> impl Iterator<Item = KeyCode> + '_ {
+        self.bindings.iter().filter(move |(_, b)| *b == byte).map(|(key, _)| *key)
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::row()
+    }
+}
+
+// Keyboard key from its name, such as "A", "7", "Space", "Left" or "Kp5"
+pub fn parse_key_name(name: &str) -> Option<KeyCode> {
+    let key = match name.to_lowercase().as_str() {
+        "a" => KeyCode::A, "b" => KeyCode::B, "c" => KeyCode::C, "d" => KeyCode::D,
+        "e" => KeyCode::E, "f" => KeyCode::F, "g" => KeyCode::G, "h" => KeyCode::H,
+        "i" => KeyCode::I, "j" => KeyCode::J, "k" => KeyCode::K, "l" => KeyCode::L,
+        "m" => KeyCode::M, "n" => KeyCode::N, "o" => KeyCode::O, "p" => KeyCode::P,
+        "q" => KeyCode::Q, "r" => KeyCode::R, "s" => KeyCode::S, "t" => KeyCode::T,
+        "u" => KeyCode::U, "v" => KeyCode::V, "w" => KeyCode::W, "x" => KeyCode::X,
+        "y" => KeyCode::Y, "z" => KeyCode::Z,
+        "0" => KeyCode::Key0, "1" => KeyCode::Key1, "2" => KeyCode::Key2, "3" => KeyCode::Key3,
+        "4" => KeyCode::Key4, "5" => KeyCode::Key5, "6" => KeyCode::Key6, "7" => KeyCode::Key7,
+        "8" => KeyCode::Key8, "9" => KeyCode::Key9,
+        "kp0" => KeyCode::Kp0, "kp1" => KeyCode::Kp1, "kp2" => KeyCode::Kp2, "kp3" => KeyCode::Kp3,
+        "kp4" => KeyCode::Kp4, "kp5" => KeyCode::Kp5, "kp6" => KeyCode::Kp6, "kp7" => KeyCode::Kp7,
+        "kp8" => KeyCode::Kp8, "kp9" => KeyCode::Kp9,
+        "up" => KeyCode::Up, "down" => KeyCode::Down, "left" => KeyCode::Left, "right" => KeyCode::Right,
+        "space" => KeyCode::Space, "enter" => KeyCode::Enter, "tab" => KeyCode::Tab,
+        "comma" | "," => KeyCode::Comma, "period" | "." => KeyCode::Period,
+        "semicolon" | ";" => KeyCode::Semicolon, "slash" | "/" => KeyCode::Slash,
+        "minus" | "-" => KeyCode::Minus, "equal" | "=" => KeyCode::Equal,
+        _ => return None,
+    };
+
+    return Some(key);
+}
+
+// Short label for a key, for the keymap overlay
+pub fn key_label(key: KeyCode) -> String {
+    let name = format!("{:?}", key);
+
+    return match name.strip_prefix("Key") {
+        Some(digit) => digit.to_string(),
+        None => name,
+    };
+}
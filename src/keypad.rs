@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+// Events kept waiting for the VM, the oldest is applied right away past this
+static MAX_EVENTS: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    // VM cycle from which the event applies
+    pub cycle: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// The 16-key hex keypad. Frontends queue press and release events, which the VM
+// applies between instructions so that every press is seen by at least one instruction.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Keypad {
+    // Bit K is set when key K is down
+    mask: u16,
+    events: VecDeque<KeyEvent>,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad::default()
+    }
+
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    // Replace the key state and drop pending events, for save states
+    pub fn set_mask(&mut self, mask: u16) {
+        self.mask = mask;
+        self.events.clear();
+    }
+
+    pub fn is_down(&self, key: u8) -> bool {
+        key < 16 && self.mask & 1 << key != 0
+    }
+
+    // Keys currently down, in increasing order
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..16).filter(move |key| self.is_down(*key))
+    }
+
+    // Key state once every pending event has been applied
+    pub fn latest_mask(&self) -> u16 {
+        self.events.iter().fold(self.mask, apply)
+    }
+
+    pub fn push(&mut self, event: KeyEvent) {
+        if self.events.len() >= MAX_EVENTS {
+            let oldest = self.events.pop_front().unwrap();
+            self.mask = apply(self.mask, &oldest);
+        }
+
+        self.events.push_back(KeyEvent { key: event.key & 0xF, ..event });
+    }
+
+    // Apply the events due at `cycle`, stopping before one that would undo a change
+    // made by this call so that short presses aren't lost
+    pub fn update(&mut self, cycle: u64) {
+        let mut changed: u16 = 0;
+
+        while let Some(event) = self.events.front() {
+            let bit = 1 << event.key;
+
+            if event.cycle > cycle || changed & bit != 0 {
+                break;
+            }
+
+            let mask = apply(self.mask, event);
+            changed |= mask ^ self.mask;
+            self.mask = mask;
+            self.events.pop_front();
+        }
+    }
+}
+
+fn apply(mask: u16, event: &KeyEvent) -> u16 {
+    if event.pressed {
+        mask | 1 << event.key
+    } else {
+        mask & !(1 << event.key)
+    }
+}
@@ -0,0 +1,22 @@
+#![allow(clippy::needless_return)]
+
+pub mod asm;
+pub mod audio;
+pub mod chip8;
+pub mod cli;
+pub mod config;
+pub mod debugger;
+pub mod disasm;
+pub mod gamepad;
+pub mod headless;
+pub mod instruction;
+pub mod keymap;
+pub mod keypad;
+pub mod quirks;
+pub mod reader;
+pub mod rewind;
+pub mod runner;
+pub mod state;
+pub mod trace;
+pub mod tui;
+pub mod bench;
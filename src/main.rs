@@ -1,23 +1,42 @@
-mod chip8;
-mod reader;
-mod runner;
-mod bench;
+#![allow(clippy::needless_return)]
 
-use macroquad::{prelude::Conf, miniquad::conf::Platform};
-use runner::*;
-use chip8::VM;
-use reader::*;
-use std::env;
+use chipr::audio::{AudioSettings, BuzzerRecorder};
+use chipr::chip8::{StepResult, TimerMode, VM};
+use chipr::cli::*;
+use chipr::config::{self, Config, KeymapConfig, RomConfig};
+use chipr::debugger::Debugger;
+use chipr::gamepad::GamepadBindings;
+use chipr::keymap::Keymap;
+use chipr::reader::*;
+use chipr::runner::*;
+use chipr::trace::Tracer;
+use chipr::{asm, bench, disasm, headless, tui};
+use clap::Parser;
+use macroquad::{prelude::Conf, miniquad::conf::Platform, Window};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
 
-static ARGUMENT_PARSE_ERROR: &str = "Could not parse argument";
-static NO_INPUT_FILE_ERROR: &str = "No input file provided";
+static STATE_READ_ERROR: &str = "Could not read save state";
+static STATE_LOAD_ERROR: &str = "Could not load save state";
+static WAV_WRITE_ERROR: &str = "Could not write WAV file";
+static ROM_WRITE_ERROR: &str = "Could not write ROM file";
+static KEYMAP_CONFIG_ERROR: &str = "Invalid keymap in configuration";
+static SCREENSHOT_WRITE_ERROR: &str = "Could not write screenshot";
+static TERMINAL_ERROR: &str = "Could not run in the terminal";
+static GAMEPAD_CONFIG_ERROR: &str = "Invalid gamepad bindings in configuration";
+static TRACE_CREATE_ERROR: &str = "Could not create trace";
 
-fn create_conf() -> Conf {
+const DEFAULT_RATE: u32 = 450;
+
+fn create_conf(debug: bool) -> Conf {
     Conf {
         window_title: String::from("Chipr"),
         window_resizable: false,
         window_height: 256,
-        window_width: 512,
+        window_width: if debug { 512 + DEBUG_PANEL_WIDTH as i32 } else { 512 },
         high_dpi: false,
         fullscreen: false,
         sample_count: 1,
@@ -26,59 +45,289 @@ fn create_conf() -> Conf {
     }
 }
 
-#[macroquad::main(create_conf)]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
+fn read_program(path: &Path) -> Result<Vec<u8>, String> {
+    read_rom_bytes(&path.to_string_lossy()).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+// The explicit config file, or the one in the user's config directory if it exists
+fn load_config(path: &Option<PathBuf>) -> Result<Config, Box<dyn Error>> {
+    match path.clone().or_else(Config::default_path) {
+        Some(path) => Ok(Config::load(&path)?),
+        None => Ok(Config::default()),
+    }
+}
+
+// A VM with its ROM loaded, and the settings found for that ROM
+struct Machine {
+    vm: VM,
+    rate: u32,
+    rom_config: RomConfig,
+}
+
+// Command line options take precedence over the ROM's config, which takes precedence over the defaults
+fn create_vm(machine: &MachineArgs, rate: Option<u32>, config: &Config) -> Result<Machine, Box<dyn Error>> {
+    let program = read_program(&machine.rom)?;
+    let rom_config = config.for_rom(&config::sha1_hex(&program))?;
+
+    let quirks = machine.quirks.or(rom_config.quirks()?).unwrap_or_default();
+    let load_address = machine.load_address.or(rom_config.load_address).unwrap_or(DEFAULT_LOAD_ADDRESS);
+    let rate = rate.or(rom_config.rate).unwrap_or(DEFAULT_RATE);
+
+    let options = LoadOptions {
+        load_address,
+        memory_size: quirks.memory_size,
+    };
+    let rom = load_program(&program, options).map_err(|error| format!("{}: {}", machine.rom.display(), error))?;
+
+    let mut vm = VM::new_with_freq(rate);
+    vm.set_quirks(quirks);
+    vm.set_error_policy(machine.on_error);
+    vm.load_rom(rom, load_address);
+    vm.init_font();
+
+    return Ok(Machine { vm, rate, rom_config });
+}
+
+fn apply_frontend_config(settings: &mut RunSettings, rom_config: &RomConfig) -> Result<(), Box<dyn Error>> {
+    if let Some(palette) = rom_config.palette()? {
+        for (color, [r, g, b]) in settings.palette.iter_mut().zip(palette) {
+            *color = macroquad::color::Color::from_rgba(r, g, b, 255);
+        }
+    }
+
+    let keymap = match &rom_config.keymap {
+        Some(KeymapConfig::Layout(name)) => Some(Keymap::parse(name)),
+        Some(KeymapConfig::Keys(names)) => Some(Keymap::from_key_names(names)),
+        None => None,
+    };
+    if let Some(keymap) = keymap {
+        settings.keymap = keymap.map_err(|error| format!("{}: {}", KEYMAP_CONFIG_ERROR, error))?;
+    }
+
+    if let Some(overrides) = &rom_config.gamepad {
+        settings.gamepad = GamepadBindings::default()
+            .with_overrides(overrides)
+            .map_err(|error| format!("{}: {}", GAMEPAD_CONFIG_ERROR, error))?;
+    }
+
+    return Ok(());
+}
+
+fn run(args: RunArgs, config: &Config) -> Result<(), Box<dyn Error>> {
+    let Machine { mut vm, rate, rom_config } = create_vm(&args.machine, args.rate, config)?;
+
+    if let Some(state_path) = &args.state {
+        let state = fs::read(state_path).map_err(|error| format!("{}: {}", STATE_READ_ERROR, error))?;
+        vm.load_state(&state).map_err(|error| format!("{}: {}", STATE_LOAD_ERROR, error))?;
+    }
+
+    if let Some(trace_path) = &args.trace {
+        let tracer = Tracer::create(trace_path, args.trace_options.settings())
+            .map_err(|error| format!("{}: {}", TRACE_CREATE_ERROR, error))?;
+        vm.tracer = Some(tracer);
+    }
+
+    let mut settings = RunSettings::new(rate);
+    settings.cycles_per_frame = args
+        .cycles_per_frame
+        .or(rom_config.cycles_per_frame)
+        .unwrap_or(settings.cycles_per_frame);
+    settings.threaded = args.threaded;
+    settings.audio = AudioSettings {
+        waveform: args.wave,
+        frequency: args.freq,
+        volume: args.volume,
+        muted: args.mute,
+    };
+    settings.rewind_seconds = args.rewind;
+    apply_frontend_config(&mut settings, &rom_config)?;
+    if let Some(keymap) = args.keymap {
+        settings.keymap = keymap;
+    }
+
+    if args.debug || !args.breakpoints.is_empty() || !args.watchpoints.is_empty() {
+        let mut debugger = Debugger::new();
+        debugger.breakpoints = args.breakpoints;
+        debugger.watchpoints = args.watchpoints;
+        settings.debugger = Some(debugger);
+    }
+
+    if let Some(wav_path) = args.wav {
+        // Run without a window and record the buzzer output
+        let mut recorder = BuzzerRecorder::new(settings.audio);
+        let mut result = Ok(());
+
+        for _ in 0..args.frames {
+            // Record the buzzer state of the frame before the timers tick
+            let sound_on = vm.sound_timer > 0;
+
+            match vm.run_frame(settings.cycles_per_frame) {
+                Ok(StepResult::Continue) => {}
+                Ok(StepResult::Halted) => break,
+                Err(error) => {
+                    // Keep what was recorded up to the error
+                    result = Err(error.into());
+                    break;
+                }
+            }
+
+            recorder.record_frame(sound_on);
+        }
+
+        recorder.write_wav(&wav_path.to_string_lossy()).map_err(|error| format!("{}: {}", WAV_WRITE_ERROR, error))?;
+        return result;
+    }
+
+    if args.tui {
+        tui::run(vm, &settings, args.glyphs).map_err(|error| format!("{}: {}", TERMINAL_ERROR, error))?;
+        return Ok(());
+    }
 
-    let mut filename = String::new();
-    let mut rate: u32 = 450;
-    let mut dump = false;
-    let mut benchmark = false;
+    if args.headless {
+        let duration = match args.cycles {
+            Some(cycles) => headless::Duration::Cycles(cycles),
+            None => headless::Duration::Frames(args.frames),
+        };
+        let script = match &args.input {
+            Some(path) => headless::InputScript::load(path)?,
+            None => headless::InputScript::default(),
+        };
 
-    for arg in args.iter().skip(1) {
-        if arg.starts_with("rom=") {
-            filename = arg[4..arg.len()].to_string();
+        let report = headless::run(&mut vm, duration, settings.cycles_per_frame, &script);
+        println!("{}", serde_json::to_string(&report)?);
+
+        if let Some(path) = &args.screenshot {
+            let palette = settings.palette.map(|color| [(color.r * 255.0) as u8, (color.g * 255.0) as u8, (color.b * 255.0) as u8]);
+            headless::write_screen(&vm.screen, &palette, path)
+                .map_err(|error| format!("{}: {}", SCREENSHOT_WRITE_ERROR, error))?;
         }
-        else if arg.starts_with("rate="){
-            let n = arg[5..arg.len()]
-            .parse::<usize>()
-            .expect(&format!("{} {}", ARGUMENT_PARSE_ERROR, arg));
-            rate = n as u32;
+
+        // Fail the run on errors so that scripts notice them
+        return match report.error {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        };
+    }
+
+    let rom_path = args.machine.rom.to_string_lossy().into_owned();
+    Window::from_config(create_conf(settings.debugger.is_some()), async move {
+        start(rom_path, vm, settings).await;
+    });
+
+    return Ok(());
+}
+
+fn trace(args: TraceArgs, config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut vm = create_vm(&args.machine, args.rate, config)?.vm;
+    vm.set_timer_mode(TimerMode::Instructions);
+    vm.tracer = Some(Tracer::new(Box::new(io::stdout()), args.trace_options.settings())?);
+
+    for _ in 0..args.cycles {
+        if vm.next()? == StepResult::Halted {
+            break;
         }
-        else if arg.eq("--dump"){
-            dump = true;
+    }
+
+    // Dropping the VM ends the trace, which prints what --trace-last kept
+
+    return Ok(());
+}
+
+fn execute(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let config = load_config(&cli.config)?;
+
+    match cli.command {
+        Command::Run(args) => run(*args, &config)?,
+        Command::Bench(args) => {
+            let mut vm = create_vm(&args.machine, Some(100_000_000), &config)?.vm;
+            vm.set_timer_mode(TimerMode::Instructions);
+
+            let mut bench = bench::Bench::new(vm);
+            bench.test();
+            bench.print_results();
         }
-        else if arg.eq("--benchmark"){
-            benchmark = true;
+        Command::Dump(machine) => create_vm(&machine, None, &config)?.vm.dump_memory(),
+        Command::Disasm(args) => {
+            let program = read_program(&args.rom)?;
+            print!("{}", disasm::disassemble(&program, args.load_address));
         }
-        else {
-            panic!("{} {}", ARGUMENT_PARSE_ERROR, arg);
+        Command::Trace(args) => trace(args, &config)?,
+        Command::Asm(args) => {
+            let rom = asm::assemble_file(&args.input)?;
+            fs::write(&args.output, rom).map_err(|error| format!("{}: {}", ROM_WRITE_ERROR, error))?;
         }
     }
 
-    if filename == "" {
-        panic!("{}", NO_INPUT_FILE_ERROR);
+    return Ok(());
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if let Err(error) = execute(cli) {
+        eprintln!("chipr: {}", error);
+        process::exit(1);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use chipr::quirks::Quirks;
+
+    use super::*;
+
+    // A ROM, a database entry and a config file entry for it, in a fresh directory
+    fn setup(name: &str) -> (PathBuf, Config) {
+        let directory = std::env::temp_dir().join(format!("chipr-main-{}-{}", name, process::id()));
+        fs::create_dir_all(&directory).unwrap();
 
-    if dump {
-        let mut vm = VM::new();
-        vm.load_rom(read_rom(filename));
-        vm.init_font();
+        let rom = directory.join("game.ch8");
+        fs::write(&rom, [0x00, 0xE0]).unwrap();
+        let sha1 = config::sha1_hex(&[0x00, 0xE0]);
 
-        vm.dump_memory();
+        let database = directory.join("programs.json");
+        let entry = format!(r#"[{{ "roms": {{ "{}": {{ "tickrate": 10, "platforms": ["xochip"] }} }} }}]"#, sha1);
+        fs::write(&database, entry).unwrap();
+
+        let path = directory.join("config.toml");
+        let text = format!(
+            "database = {:?}\n[defaults]\nrate = 300\nload_address = 0x300\n[roms.{}]\nquirks = \"schip-legacy\"\n",
+            database, sha1
+        );
+        fs::write(&path, text).unwrap();
+
+        return (rom, Config::load(&path).unwrap());
     }
-    else if benchmark {
-        let mut vm = VM::new_with_freq(100_000_000);
-        vm.load_rom(read_rom(filename));
-        vm.init_font();
 
-        let mut bench = bench::Bench::new(vm);
-        bench.test();
-        bench.print_results();
+    fn machine_args(arguments: &[&str]) -> MachineArgs {
+        let arguments = ["chipr", "dump"].iter().chain(arguments);
+
+        match Cli::try_parse_from(arguments).unwrap().command {
+            Command::Dump(machine) => machine,
+            _ => unreachable!(),
+        }
     }
-    else {
-        create_vm_and_start(filename, rate).await;
+
+    #[test]
+    fn rom_settings_come_from_the_rom_entry_then_the_database_then_the_defaults() {
+        let (rom, config) = setup("config");
+        let machine = create_vm(&machine_args(&[&rom.to_string_lossy()]), None, &config).unwrap();
+
+        assert_eq!(machine.vm.quirks, Quirks::schip_legacy());
+        assert_eq!(machine.rate, 600);
+        assert_eq!(machine.rom_config.cycles_per_frame, Some(10));
+        assert_eq!(machine.vm.pc, 0x300);
     }
 
-    Ok(())
+    #[test]
+    fn command_line_options_override_the_config() {
+        let (rom, config) = setup("cli");
+        let arguments = machine_args(&[&rom.to_string_lossy(), "--quirks", "cosmac-vip", "--load-address", "0x600"]);
+        let machine = create_vm(&arguments, Some(1000), &config).unwrap();
+
+        assert_eq!(machine.vm.quirks, Quirks::cosmac_vip());
+        assert_eq!(machine.rate, 1000);
+        assert_eq!(machine.vm.pc, 0x600);
+        assert_eq!(machine.vm.memory[0x601], 0xE0);
+    }
 }
@@ -0,0 +1,118 @@
+// Behaviors that differ between CHIP-8 interpreters. Each flag is named after
+// the quirk it enables, so that a value of `true` means "behave like the
+// interpreter that introduced this quirk".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VX in place and ignore VY (CHIP-48 / SUPER-CHIP)
+    pub shift: bool,
+    // FX55/FX65 increment I by X + 1 (COSMAC VIP)
+    pub load_store: bool,
+    // BNNN behaves as BXNN and adds VX instead of V0 (CHIP-48 / SUPER-CHIP)
+    pub jump: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0 (COSMAC VIP)
+    pub vf_reset: bool,
+    // DXYN clips sprites at the screen edges instead of wrapping them
+    pub clipping: bool,
+    // DXYN waits for the next timer tick (vertical blank) before drawing
+    pub display_wait: bool,
+    // FX0A completes when the key is released rather than when it is pressed (COSMAC VIP)
+    pub key_release: bool,
+    // In hi-res, DXYN sets VF to the number of sprite rows that collided or were
+    // clipped at the bottom instead of 0 or 1 (SUPER-CHIP 1.1)
+    pub collision_rows: bool,
+    // Bytes of memory on the platform, which bounds the size of its ROMs
+    pub memory_size: usize,
+}
+
+pub static PRESET_NAMES: &[&str] = &["chipr", "cosmac-vip", "schip-legacy", "schip-modern", "xo-chip"];
+
+impl Quirks {
+    // Historical chipr behavior, kept as the default so existing setups don't change
+    pub fn chipr() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: false,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            key_release: false,
+            collision_rows: false,
+            memory_size: 0x10000,
+        }
+    }
+
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift: false,
+            load_store: true,
+            jump: false,
+            vf_reset: true,
+            clipping: true,
+            display_wait: true,
+            key_release: true,
+            collision_rows: false,
+            memory_size: 0x1000,
+        }
+    }
+
+    pub fn schip_legacy() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: false,
+            jump: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: true,
+            key_release: true,
+            collision_rows: true,
+            memory_size: 0x1000,
+        }
+    }
+
+    pub fn schip_modern() -> Quirks {
+        Quirks {
+            shift: true,
+            load_store: false,
+            jump: true,
+            vf_reset: false,
+            clipping: true,
+            display_wait: false,
+            key_release: true,
+            collision_rows: false,
+            memory_size: 0x1000,
+        }
+    }
+
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift: false,
+            load_store: true,
+            jump: false,
+            vf_reset: false,
+            clipping: false,
+            display_wait: false,
+            key_release: true,
+            collision_rows: false,
+            memory_size: 0x10000,
+        }
+    }
+
+    // Look up a preset by name, accepting a few short aliases
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name.to_lowercase().as_str() {
+            "chipr" | "default" => Some(Quirks::chipr()),
+            "cosmac-vip" | "vip" | "chip8" | "chip-8" => Some(Quirks::cosmac_vip()),
+            "schip-legacy" | "schip" | "schip1.1" => Some(Quirks::schip_legacy()),
+            "schip-modern" => Some(Quirks::schip_modern()),
+            "xo-chip" | "xo" | "xochip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::chipr()
+    }
+}
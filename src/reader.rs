@@ -1,21 +1,360 @@
+use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::io;
-use std::io::Read;
+use std::path::Path;
 
-static FILE_READ_ERROR: &str = "Could not read file";
-static FILE_READING_ERROR: &str = "Error while reading file";
+use crate::asm::{self, AsmError};
+use crate::chip8::{FONT_END, MEMORY_SIZE};
 
-pub fn read_rom(filename: String) -> [u8; 4096] {
-    let f = fs::File::open(filename).expect(FILE_READ_ERROR);
-    let mut reader = io::BufReader::new(f);
-    let mut rom_vec: Vec<u8> = vec![];
-    let mut rom: [u8; 4096] = [0; 4096];
+// Where programs are loaded unless told otherwise
+pub const DEFAULT_LOAD_ADDRESS: u16 = 0x200;
 
-    reader.read_to_end(&mut rom_vec).expect(FILE_READING_ERROR);
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    TooLarge { size: usize, max: usize },
+    InvalidLoadAddress(u16),
+    LoadAddressInFont(u16),
+    InvalidHex { line: usize, column: usize },
+    InvalidCartridge(&'static str),
+    Assembly(AsmError),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(error) => write!(f, "Could not read ROM: {}", error),
+            RomError::TooLarge { size, max } => {
+                write!(f, "ROM is {} bytes but at most {} fit in memory at this load address", size, max)
+            }
+            RomError::InvalidLoadAddress(address) => {
+                write!(f, "Load address {:#05X} leaves no room for an instruction in memory", address)
+            }
+            RomError::LoadAddressInFont(address) => {
+                write!(f, "Load address {:#05X} overlaps the font, which ends at {:#05X}", address, FONT_END)
+            }
+            RomError::InvalidHex { line, column } => write!(f, "Invalid hex dump at {}:{}", line, column),
+            RomError::InvalidCartridge(reason) => write!(f, "Invalid Octo cartridge: {}", reason),
+            RomError::Assembly(error) => write!(f, "Could not assemble cartridge: {}", error),
+        }
+    }
+}
+
+impl Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(error: io::Error) -> Self {
+        RomError::Io(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadOptions {
+    pub load_address: u16,
+    // Memory size of the target platform, 4K for CHIP-8 and SUPER-CHIP
+    pub memory_size: usize,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            load_address: DEFAULT_LOAD_ADDRESS,
+            memory_size: MEMORY_SIZE,
+        }
+    }
+}
+
+// Parse a textual hex dump such as "00E0 A22A" or "0x00, 0xE0", with # comments
+fn parse_hex_text(text: &str) -> Result<Vec<u8>, RomError> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let content = line.split('#').next().unwrap_or("");
+        let mut offset = 0;
+
+        for word in content.split(|c: char| c.is_whitespace() || c == ',') {
+            let column = offset + 1;
+            offset += word.len() + 1;
+
+            let digits = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")).unwrap_or(word);
+            if digits.is_empty() {
+                continue;
+            }
+
+            if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(RomError::InvalidHex { line: line_index + 1, column });
+            }
+
+            for pair in (0..digits.len()).step_by(2) {
+                bytes.push(u8::from_str_radix(&digits[pair..pair + 2], 16).unwrap());
+            }
+        }
+    }
+
+    return Ok(bytes);
+}
+
+// Octo cartridges hide their payload in the low 2 bits of every pixel's palette index,
+// most significant bits first and across all frames. The payload is a 32-bit big-endian
+// length followed by a JSON object holding the program source and its options.
+fn parse_cartridge(data: &[u8], path: &Path) -> Result<Vec<u8>, RomError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+
+    let mut decoder = options
+        .read_info(data)
+        .map_err(|_| RomError::InvalidCartridge("not a GIF image"))?;
+
+    let mut payload: Vec<u8> = Vec::new();
+    let mut byte: u8 = 0;
+    let mut bits = 0;
+
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|_| RomError::InvalidCartridge("corrupt GIF frame"))?
+    {
+        for index in frame.buffer.iter() {
+            byte = (byte << 2) | (index & 0b11);
+            bits += 2;
+
+            if bits == 8 {
+                payload.push(byte);
+                byte = 0;
+                bits = 0;
+            }
+        }
+    }
+
+    if payload.len() < 4 {
+        return Err(RomError::InvalidCartridge("missing payload"));
+    }
+
+    let length = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    let json = payload
+        .get(4..4 + length)
+        .ok_or(RomError::InvalidCartridge("truncated payload"))?;
+
+    let cartridge: serde_json::Value =
+        serde_json::from_slice(json).map_err(|_| RomError::InvalidCartridge("payload is not JSON"))?;
+    let program = cartridge["program"]
+        .as_str()
+        .ok_or(RomError::InvalidCartridge("payload has no program"))?;
+
+    return asm::assemble(program, path).map_err(RomError::Assembly);
+}
+
+// Read the program bytes of a ROM file. Raw binaries are used as is, `.hex` and `.txt`
+// files are parsed as hex dumps and GIF images as Octo cartridges.
+pub fn read_rom_bytes(filename: &str) -> Result<Vec<u8>, RomError> {
+    let path = Path::new(filename);
+    let data = fs::read(path)?;
+
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    if data.starts_with(b"GIF8") {
+        return parse_cartridge(&data, path);
+    }
+
+    if extension == "hex" || extension == "txt" {
+        return parse_hex_text(&String::from_utf8_lossy(&data));
+    }
+
+    return Ok(data);
+}
+
+// Build a memory image from program bytes, with the program at the load address
+pub fn load_program(program: &[u8], options: LoadOptions) -> Result<Vec<u8>, RomError> {
+    let memory_size = options.memory_size.min(MEMORY_SIZE);
+
+    // The VM reads whole opcodes from the load address
+    if options.load_address as usize + 2 > memory_size {
+        return Err(RomError::InvalidLoadAddress(options.load_address));
+    }
+
+    // The font is written over the image once it is loaded
+    if (options.load_address as usize) < FONT_END {
+        return Err(RomError::LoadAddressInFont(options.load_address));
+    }
+
+    let max = memory_size - options.load_address as usize;
+    if program.len() > max {
+        return Err(RomError::TooLarge { size: program.len(), max });
+    }
+
+    let mut rom: Vec<u8> = vec![0; MEMORY_SIZE];
+    let start = options.load_address as usize;
+    rom[start..start + program.len()].copy_from_slice(program);
+
+    return Ok(rom);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn options(load_address: u16, memory_size: usize) -> LoadOptions {
+        LoadOptions { load_address, memory_size }
+    }
+
+    // A one-line GIF carrying `payload` in the low 2 bits of its pixels, like Octo writes them
+    fn cartridge(payload: &[u8]) -> Vec<u8> {
+        let mut pixels: Vec<u8> = Vec::new();
+        for byte in payload {
+            for shift in [6, 4, 2, 0] {
+                // The high bits of the index pick the color and are ignored
+                pixels.push(0b100 | (byte >> shift) & 0b11);
+            }
+        }
+
+        let palette = [0u8; 8 * 3];
+        let mut gif: Vec<u8> = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, pixels.len() as u16, 1, &palette).unwrap();
+            let frame = gif::Frame {
+                width: pixels.len() as u16,
+                height: 1,
+                buffer: Cow::Borrowed(&pixels),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+
+        return gif;
+    }
+
+    fn json_payload(json: &str) -> Vec<u8> {
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json.as_bytes());
+
+        return payload;
+    }
+
+    fn cartridge_error(data: &[u8]) -> &'static str {
+        match parse_cartridge(data, Path::new("cart.gif")) {
+            Err(RomError::InvalidCartridge(reason)) => reason,
+            other => panic!("expected an invalid cartridge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hex_dumps_accept_words_prefixes_commas_and_comments() {
+        let bytes = parse_hex_text("00E0 A22A\n# a comment line\n\n12 34  # trailing comment\n").unwrap();
+        assert_eq!(bytes, [0x00, 0xE0, 0xA2, 0x2A, 0x12, 0x34]);
+
+        let bytes = parse_hex_text("0x00, 0xE0,0X12\t0xab").unwrap();
+        assert_eq!(bytes, [0x00, 0xE0, 0x12, 0xAB]);
+    }
+
+    #[test]
+    fn hex_dump_errors_point_at_the_word() {
+        let position = |text: &str| match parse_hex_text(text) {
+            Err(RomError::InvalidHex { line, column }) => (line, column),
+            other => panic!("expected invalid hex, got {:?}", other),
+        };
+
+        // An odd number of digits
+        assert_eq!(position("00E"), (1, 1));
+        assert_eq!(position("00E0 ABC"), (1, 6));
+        assert_eq!(position("0x00, 0x0"), (1, 7));
+        // Not a hex digit
+        assert_eq!(position("00E0\n  12 3G"), (2, 6));
+    }
+
+    #[test]
+    fn programs_are_placed_at_the_load_address() {
+        let rom = load_program(&[0x12, 0x34], options(0x600, 0x1000)).unwrap();
 
-    for (i, byte) in rom_vec.iter().enumerate() {
-        rom[i + 512] = byte.clone();
+        assert_eq!(rom.len(), MEMORY_SIZE);
+        assert_eq!(rom[0x600..0x602], [0x12, 0x34]);
+        assert!(rom[..0x600].iter().all(|&byte| byte == 0));
     }
 
-    return rom;
+    #[test]
+    fn load_addresses_inside_the_font_are_rejected() {
+        for load_address in [0x000, 0x050, FONT_END as u16 - 1] {
+            let result = load_program(&[0x00, 0xE0], options(load_address, 0x1000));
+            assert!(matches!(result, Err(RomError::LoadAddressInFont(address)) if address == load_address));
+        }
+
+        assert!(load_program(&[0x00, 0xE0], options(FONT_END as u16, 0x1000)).is_ok());
+    }
+
+    #[test]
+    fn rom_size_is_limited_by_the_platform_memory() {
+        let program = vec![0; 0xE01];
+
+        let result = load_program(&program, options(0x200, 0x1000));
+        assert!(matches!(result, Err(RomError::TooLarge { size: 0xE01, max: 0xE00 })));
+
+        assert!(load_program(&program[..0xE00], options(0x200, 0x1000)).is_ok());
+        assert!(load_program(&program, options(0x200, 0x10000)).is_ok());
+
+        // Larger platforms are still limited to the 64K address space
+        let result = load_program(&vec![0; 0x10000], options(0x200, 0x20000));
+        assert!(matches!(result, Err(RomError::TooLarge { size: 0x10000, max: 0xFE00 })));
+    }
+
+    #[test]
+    fn load_addresses_need_room_for_one_opcode() {
+        let result = load_program(&[], options(0xFFF, 0x1000));
+        assert!(matches!(result, Err(RomError::InvalidLoadAddress(0xFFF))));
+
+        let result = load_program(&[], options(0xFFFF, 0x10000));
+        assert!(matches!(result, Err(RomError::InvalidLoadAddress(0xFFFF))));
+
+        assert!(load_program(&[0x12, 0x34], options(0xFFE, 0x1000)).is_ok());
+        let result = load_program(&[0x12, 0x34, 0x56], options(0xFFE, 0x1000));
+        assert!(matches!(result, Err(RomError::TooLarge { size: 3, max: 2 })));
+    }
+
+    #[test]
+    fn cartridges_are_assembled_from_their_payload() {
+        let data = cartridge(&json_payload(r#"{"program": ": main clear jump main", "options": {}}"#));
+
+        let program = parse_cartridge(&data, Path::new("cart.gif")).unwrap();
+        assert_eq!(program, [0x00, 0xE0, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn broken_cartridges_are_reported() {
+        assert_eq!(cartridge_error(b"not a gif"), "not a GIF image");
+        assert_eq!(cartridge_error(&cartridge(&[0x00, 0x01])), "missing payload");
+
+        let mut truncated = json_payload(r#"{"program": "clear"}"#);
+        truncated.truncate(10);
+        assert_eq!(cartridge_error(&cartridge(&truncated)), "truncated payload");
+
+        assert_eq!(cartridge_error(&cartridge(&json_payload("program: clear"))), "payload is not JSON");
+        assert_eq!(cartridge_error(&cartridge(&json_payload(r#"{"options": {}}"#))), "payload has no program");
+
+        let data = cartridge(&json_payload(r#"{"program": "jump nowhere"}"#));
+        assert!(matches!(parse_cartridge(&data, Path::new("cart.gif")), Err(RomError::Assembly(_))));
+    }
+
+    #[test]
+    fn rom_files_are_read_by_format() {
+        let directory = env::temp_dir().join(format!("chipr-reader-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+
+        fs::write(path("raw.ch8"), [0x00, 0xE0]).unwrap();
+        fs::write(path("dump.hex"), "00E0 1200").unwrap();
+        fs::write(path("cart.gif"), cartridge(&json_payload(r#"{"program": "clear"}"#))).unwrap();
+
+        assert_eq!(read_rom_bytes(&path("raw.ch8")).unwrap(), [0x00, 0xE0]);
+        assert_eq!(read_rom_bytes(&path("dump.hex")).unwrap(), [0x00, 0xE0, 0x12, 0x00]);
+        assert_eq!(read_rom_bytes(&path("cart.gif")).unwrap(), [0x00, 0xE0]);
+        assert!(matches!(read_rom_bytes(&path("missing.ch8")), Err(RomError::Io(_))));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
 }
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+
+// Differing regions closer than this are merged into a single patch
+const PATCH_MERGE_GAP: usize = 8;
+
+// The bytes needed to turn a snapshot back into the one recorded just before it
+struct Delta {
+    length: usize,
+    patches: Vec<(usize, Vec<u8>)>,
+}
+
+impl Delta {
+    fn between(older: &[u8], newer: &[u8]) -> Delta {
+        let mut patches: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut last_end = 0;
+
+        for (offset, byte) in older.iter().enumerate() {
+            if newer.get(offset) == Some(byte) {
+                continue;
+            }
+
+            match patches.last_mut() {
+                Some((start, bytes)) if offset - last_end < PATCH_MERGE_GAP => {
+                    bytes.extend_from_slice(&older[*start + bytes.len()..=offset]);
+                }
+                _ => patches.push((offset, vec![*byte])),
+            }
+
+            last_end = offset + 1;
+        }
+
+        return Delta {
+            length: older.len(),
+            patches,
+        };
+    }
+
+    fn apply(&self, snapshot: &mut Vec<u8>) {
+        snapshot.resize(self.length, 0);
+
+        for (start, bytes) in self.patches.iter() {
+            snapshot[*start..*start + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.patches.iter().map(|(_, bytes)| bytes.len() + std::mem::size_of::<usize>()).sum()
+    }
+}
+
+// Bounded history of VM snapshots. Only the newest snapshot is kept whole,
+// older ones are stored as deltas against the snapshot that followed them.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // One snapshot per frame at 60 frames per second
+    pub fn with_seconds(seconds: u32) -> Self {
+        Self::new(seconds as usize * 60)
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            self.deltas.push_back(Delta::between(&latest, &snapshot));
+
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+
+        self.latest = Some(snapshot);
+    }
+
+    // Step back one snapshot and return it, None when the history is exhausted
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+
+        delta.apply(latest);
+
+        return Some(latest.clone());
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // Approximate number of bytes used by the history
+    pub fn memory_usage(&self) -> usize {
+        let latest = self.latest.as_ref().map_or(0, |latest| latest.len());
+
+        return latest + self.deltas.iter().map(|delta| delta.size()).sum::<usize>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A snapshot of `length` bytes with `value` written at the given offsets
+    fn snapshot(length: usize, changes: &[(usize, u8)]) -> Vec<u8> {
+        let mut snapshot = vec![0; length];
+        for (offset, value) in changes {
+            snapshot[*offset] = *value;
+        }
+
+        return snapshot;
+    }
+
+    fn round_trip(older: &[u8], newer: &[u8]) -> Vec<u8> {
+        let mut restored = newer.to_vec();
+        Delta::between(older, newer).apply(&mut restored);
+
+        return restored;
+    }
+
+    #[test]
+    fn delta_restores_the_older_snapshot() {
+        let older = snapshot(64, &[(0, 1), (10, 2), (63, 3)]);
+        let newer = snapshot(64, &[(0, 9), (11, 2), (40, 7)]);
+
+        assert_eq!(round_trip(&older, &newer), older);
+        assert_eq!(round_trip(&newer, &older), newer);
+        assert_eq!(round_trip(&older, &older), older);
+    }
+
+    #[test]
+    fn delta_restores_snapshots_of_another_length() {
+        let short = snapshot(16, &[(3, 1)]);
+        let long = snapshot(32, &[(3, 2), (20, 5)]);
+
+        assert_eq!(round_trip(&short, &long), short);
+        assert_eq!(round_trip(&long, &short), long);
+    }
+
+    #[test]
+    fn delta_merges_nearby_changes_only() {
+        let older = snapshot(64, &[(0, 1), (4, 1), (40, 1)]);
+        let delta = Delta::between(&older, &snapshot(64, &[]));
+
+        // The bytes in between the first two changes are copied as well
+        assert_eq!(delta.patches, vec![(0, vec![1, 0, 0, 0, 1]), (40, vec![1])]);
+        assert!(Delta::between(&older, &older).patches.is_empty());
+    }
+
+    #[test]
+    fn buffer_steps_back_through_every_snapshot() {
+        let mut buffer = RewindBuffer::new(10);
+        for frame in 0..4 {
+            buffer.push(snapshot(32, &[(frame, 1)]));
+        }
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop(), Some(snapshot(32, &[(2, 1)])));
+        assert_eq!(buffer.pop(), Some(snapshot(32, &[(1, 1)])));
+        assert_eq!(buffer.pop(), Some(snapshot(32, &[(0, 1)])));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn buffer_forgets_the_oldest_snapshots_past_its_capacity() {
+        let mut buffer = RewindBuffer::new(2);
+        for frame in 0..6 {
+            buffer.push(snapshot(32, &[(frame, frame as u8 + 1)]));
+        }
+
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.pop(), Some(snapshot(32, &[(4, 5)])));
+        assert_eq!(buffer.pop(), Some(snapshot(32, &[(3, 4)])));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn stepping_back_past_the_oldest_snapshot_keeps_the_history_usable() {
+        let mut buffer = RewindBuffer::new(4);
+        assert_eq!(buffer.pop(), None);
+
+        buffer.push(snapshot(8, &[(0, 1)]));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.pop(), None);
+
+        // Recording resumes from the snapshot that was reached
+        buffer.push(snapshot(8, &[(1, 1)]));
+        assert_eq!(buffer.pop(), Some(snapshot(8, &[(0, 1)])));
+    }
+
+    #[test]
+    fn with_seconds_holds_one_snapshot_per_frame() {
+        let mut buffer = RewindBuffer::with_seconds(1);
+        for frame in 0..100 {
+            buffer.push(vec![frame as u8; 4]);
+        }
+
+        assert_eq!(buffer.len(), 60);
+    }
+
+    #[test]
+    fn history_uses_less_memory_than_full_copies() {
+        let mut buffer = RewindBuffer::new(100);
+        for frame in 0..100 {
+            buffer.push(snapshot(4096, &[(frame, 1)]));
+        }
+
+        assert!(buffer.memory_usage() < 4096 * 2);
+    }
+}
@@ -1,93 +1,554 @@
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 use std::iter;
 use std::time::Duration;
+use std::time::Instant;
 
+use macroquad::audio::*;
 use macroquad::prelude::*;
 
+use crate::audio::*;
 use crate::chip8::*;
-use crate::reader::*;
-
-static KEYMAP: &'static [(KeyCode, u8)] = &[
-    (KeyCode::A, 0x0),
-    (KeyCode::Z, 0x1),
-    (KeyCode::E, 0x2),
-    (KeyCode::R, 0x3),
-    (KeyCode::T, 0x4),
-    (KeyCode::Y, 0x5),
-    (KeyCode::U, 0x6),
-    (KeyCode::I, 0x7),
-    (KeyCode::O, 0x8),
-    (KeyCode::P, 0x9),
-    (KeyCode::Q, 0xA),
-    (KeyCode::S, 0xB),
-    (KeyCode::D, 0xC),
-    (KeyCode::F, 0xD),
-    (KeyCode::G, 0xE),
-    (KeyCode::H, 0xF),
-];
+use crate::debugger::*;
+use crate::gamepad::{GamepadBindings, Gamepads, Hotkey};
+use crate::instruction::decode;
+use crate::keymap::{self, Keymap, KEYPAD};
+use crate::rewind::RewindBuffer;
+
+// Colors for each combination of the two XO-CHIP bitplanes
+static PALETTE: [Color; 4] = [BLACK, WHITE, ORANGE, GRAY];
+
+static TIMER_PERIOD: Duration = Duration::from_nanos(1_000_000_000 / 60);
 
 // Launch a thread responsible for the VM backend
-fn launch_vm_thread(vm_shared: Arc<Mutex<VM>>, target_freq: u32){
+fn launch_vm_thread(vm_shared: Arc<Mutex<VM>>, target_freq: u32, paused: Arc<AtomicBool>){
     thread::spawn(move || {
+        let mut last_timer_tick = Instant::now();
+
         // Ticker with infinite iterator
         for _ in ticker::Ticker::new(iter::repeat(()), Duration::from_nanos(1_000_000_000 / (target_freq as u64))){
-            if vm_shared.lock().unwrap().next() == 0 {
+            if paused.load(Ordering::Relaxed) {
+                // Timers resume from where they were, instead of catching up on the pause
+                last_timer_tick = Instant::now();
+                continue;
+            }
+
+            let mut vm = vm_shared.lock().unwrap();
+
+            // Timers follow the wall clock, catching up if the ticker falls behind
+            while last_timer_tick.elapsed() >= TIMER_PERIOD {
+                vm.tick_timers();
+                last_timer_tick += TIMER_PERIOD;
+            }
+
+            if vm.next() != Ok(StepResult::Continue) {
                 break;
             };
         }
     });
 }
 
-pub async fn create_vm_and_start(rom_path: String, target_freq: u32) {
-    let vm_shared = Arc::new(Mutex::new(VM::new_with_freq(target_freq)));
+static MUTE_KEY: KeyCode = KeyCode::M;
+static SAVE_STATE_KEY: KeyCode = KeyCode::F5;
+static LOAD_STATE_KEY: KeyCode = KeyCode::F9;
+static PREVIOUS_SLOT_KEY: KeyCode = KeyCode::F6;
+static NEXT_SLOT_KEY: KeyCode = KeyCode::F7;
+static REWIND_KEY: KeyCode = KeyCode::Backspace;
+static DEBUG_CONTINUE_KEY: KeyCode = KeyCode::F8;
+static DEBUG_STEP_KEY: KeyCode = KeyCode::F10;
+static DEBUG_BREAKPOINT_KEY: KeyCode = KeyCode::F2;
+static KEYMAP_OVERLAY_KEY: KeyCode = KeyCode::F1;
+static PAUSE_KEY: KeyCode = KeyCode::F3;
+static RESET_KEY: KeyCode = KeyCode::F4;
+
+// Width of the debugger panel drawn to the right of the display
+pub static DEBUG_PANEL_WIDTH: f32 = 256.0;
+
+static STATE_SLOTS: u8 = 10;
+static STATE_LOAD_ERROR: &str = "Could not load save state";
 
-    // Specific scope so that the mutex is unlocked after vm init
-    {
-        let mut vm = vm_shared.lock().unwrap();
-        vm.load_rom(read_rom(rom_path));
-        vm.init_font();
+// How long status messages stay on screen, in seconds
+static MESSAGE_DURATION: f64 = 2.0;
+
+pub struct RunSettings {
+    pub rate: u32,
+    pub cycles_per_frame: u32,
+    // Run the VM on its own ticker thread instead of locking it to the frame rate
+    pub threaded: bool,
+    pub audio: AudioSettings,
+    pub palette: [Color; 4],
+    pub keymap: Keymap,
+    pub gamepad: GamepadBindings,
+    // Seconds of history kept for rewinding, 0 disables it
+    pub rewind_seconds: u32,
+    pub debugger: Option<Debugger>,
+}
+
+impl RunSettings {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            cycles_per_frame: ((rate as f32 / 60.0).round() as u32).max(1),
+            threaded: false,
+            audio: AudioSettings::default(),
+            palette: PALETTE,
+            keymap: Keymap::default(),
+            gamepad: GamepadBindings::default(),
+            rewind_seconds: 30,
+            debugger: None,
+        }
     }
+}
 
-    // Launch the VM backend
-    launch_vm_thread(Arc::clone(&vm_shared), target_freq);
+// Open the frontend for a VM that already has its ROM loaded
+pub async fn start(rom_path: String, vm: VM, mut settings: RunSettings) {
+    let mut frontend = Frontend::new(rom_path, settings.audio, settings.gamepad.clone()).await;
+    frontend.palette = settings.palette;
+    frontend.keymap = settings.keymap.clone();
+    // Resetting goes back to the state the VM was started in
+    frontend.initial_state = vm.save_state();
+
+    // The debugger needs to stop the VM between instructions, so it always runs frame-locked
+    if settings.threaded && settings.debugger.is_none() {
+        let vm_shared = Arc::new(Mutex::new(vm));
+
+        // Launch the VM backend
+        launch_vm_thread(Arc::clone(&vm_shared), settings.rate, Arc::clone(&frontend.paused));
+
+        // Launch VM frontend
+        launch_threaded_frontend(vm_shared, frontend).await;
+    }
+    else {
+        // Rewinding needs the VM to stop between frames, so it is only available when frame-locked
+        if settings.rewind_seconds > 0 {
+            frontend.rewind = Some(RewindBuffer::with_seconds(settings.rewind_seconds));
+        }
 
-    // Launch VM frontend
-    launch_vm_frontend(vm_shared).await;
+        frontend.debugger = settings.debugger.take();
+
+        launch_frame_locked_frontend(vm, frontend, settings).await;
+    }
 }
 
-async fn launch_vm_frontend(vm_shared: Arc<Mutex<VM>>) {
-    loop {
-        clear_background(BLACK);
+// State of the window shared by both run modes
+struct Frontend {
+    rom_path: String,
+    palette: [Color; 4],
+    keymap: Keymap,
+    show_keymap: bool,
+    gamepads: Gamepads,
+    // Shared with the VM thread when threaded
+    paused: Arc<AtomicBool>,
+    initial_state: Vec<u8>,
+    tone: Option<Sound>,
+    volume: f32,
+    muted: bool,
+    playing: bool,
+    slot: u8,
+    message: Option<(String, f64)>,
+    rewind: Option<RewindBuffer>,
+    debugger: Option<Debugger>,
+}
+
+impl Frontend {
+    async fn new(rom_path: String, audio: AudioSettings, gamepad: GamepadBindings) -> Self {
+        Self {
+            rom_path,
+            palette: PALETTE,
+            keymap: Keymap::default(),
+            show_keymap: false,
+            gamepads: Gamepads::new(gamepad),
+            paused: Arc::new(AtomicBool::new(false)),
+            initial_state: Vec::new(),
+            // The frontend still runs when no audio device is available, just silently
+            tone: load_sound_from_bytes(&encode_wav(&generate_tone(&audio))).await.ok(),
+            volume: audio.volume,
+            muted: audio.muted,
+            playing: false,
+            slot: 0,
+            message: None,
+            rewind: None,
+            debugger: None,
+        }
+    }
+
+    fn show_message(&mut self, message: String) {
+        self.message = Some((message, get_time() + MESSAGE_DURATION));
+    }
+
+    // Quick-save slots are stored next to the ROM
+    fn slot_path(&self) -> String {
+        format!("{}.state{}", self.rom_path, self.slot)
+    }
+
+    fn save_state(&mut self, vm: &VM) {
+        let message = match fs::write(self.slot_path(), vm.save_state()) {
+            Ok(()) => format!("Saved slot {}", self.slot),
+            Err(error) => format!("Could not save slot {}: {}", self.slot, error),
+        };
+        self.show_message(message);
+    }
+
+    fn load_state(&mut self, vm: &mut VM) {
+        let result = fs::read(self.slot_path())
+            .map_err(|error| error.to_string())
+            .and_then(|state| vm.load_state(&state).map_err(|error| error.to_string()));
+
+        let message = match result {
+            Ok(()) => format!("Loaded slot {}", self.slot),
+            Err(error) => format!("Could not load slot {}: {}", self.slot, error),
+        };
+        self.show_message(message);
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
 
+    // Pause through the debugger when there is one, so that it can still step
+    fn toggle_pause(&mut self) {
+        if let Some(debugger) = self.debugger.as_mut() {
+            if debugger.paused {
+                debugger.resume();
+            } else {
+                debugger.pause(String::from("Paused"));
+            }
+            return;
+        }
+
+        self.paused.store(!self.is_paused(), Ordering::Relaxed);
+    }
+
+    fn reset(&mut self, vm: &mut VM) {
+        vm.load_state(&self.initial_state).expect(STATE_LOAD_ERROR);
+        self.show_message(String::from("Reset"));
+    }
+
+    // Handle the emulator keys and gamepad hotkeys, returns false when the user wants to quit
+    fn handle_hotkeys(&mut self, vm: &mut VM) -> bool {
         if is_key_down(KeyCode::Escape){
+            return false;
+        }
+
+        let mut hotkeys = self.gamepads.poll();
+        let keys = [
+            (PAUSE_KEY, Hotkey::Pause),
+            (RESET_KEY, Hotkey::Reset),
+            (SAVE_STATE_KEY, Hotkey::SaveState),
+            (LOAD_STATE_KEY, Hotkey::LoadState),
+        ];
+        for (key, hotkey) in keys.iter() {
+            if is_key_pressed(*key) {
+                hotkeys.push(*hotkey);
+            }
+        }
+
+        for hotkey in hotkeys {
+            match hotkey {
+                Hotkey::Pause => self.toggle_pause(),
+                Hotkey::Reset => self.reset(vm),
+                Hotkey::SaveState => self.save_state(vm),
+                Hotkey::LoadState => self.load_state(vm),
+            }
+        }
+
+        if is_key_pressed(MUTE_KEY) {
+            self.muted = !self.muted;
+        }
+
+        if is_key_pressed(KEYMAP_OVERLAY_KEY) {
+            self.show_keymap = !self.show_keymap;
+        }
+
+        if is_key_pressed(PREVIOUS_SLOT_KEY) {
+            self.slot = (self.slot + STATE_SLOTS - 1) % STATE_SLOTS;
+            self.show_message(format!("Slot {}", self.slot));
+        }
+
+        if is_key_pressed(NEXT_SLOT_KEY) {
+            self.slot = (self.slot + 1) % STATE_SLOTS;
+            self.show_message(format!("Slot {}", self.slot));
+        }
+
+        if let Some(debugger) = self.debugger.as_mut() {
+            if is_key_pressed(DEBUG_CONTINUE_KEY) {
+                if debugger.paused {
+                    debugger.resume();
+                } else {
+                    debugger.pause(String::from("Paused"));
+                }
+            }
+
+            if is_key_pressed(DEBUG_STEP_KEY) && !vm.halted {
+                if !debugger.paused {
+                    debugger.pause(String::from("Paused"));
+                }
+                // Halts and errors pause the debugger, which shows why
+                let _ = debugger.step(vm);
+            }
+
+            if is_key_pressed(DEBUG_BREAKPOINT_KEY) {
+                debugger.toggle_breakpoint(Breakpoint::Address(vm.pc));
+            }
+        }
+
+        return true;
+    }
+
+    fn is_rewinding(&self) -> bool {
+        self.rewind.is_some() && is_key_down(REWIND_KEY)
+    }
+
+    fn record_snapshot(&mut self, vm: &VM) {
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.push(vm.save_state());
+        }
+    }
+
+    // Restore the previous frame, staying on the oldest one once the history is exhausted
+    fn step_back(&mut self, vm: &mut VM) {
+        let Some(rewind) = self.rewind.as_mut() else {
+            return;
+        };
+
+        if let Some(snapshot) = rewind.pop() {
+            vm.load_state(&snapshot).expect(STATE_LOAD_ERROR);
+        }
+
+        let message = format!(
+            "Rewinding ({:.1}s left, {} KiB)",
+            rewind.len() as f32 / 60.0,
+            rewind.memory_usage() / 1024
+        );
+        self.show_message(message);
+    }
+
+    // Queue the key changes since the last frame. Keys pressed and released within
+    // the frame are queued as a press followed by a release, so the VM still sees them.
+    fn update_input(&self, vm: &mut VM) {
+        let mut down = self.gamepads.key_mask();
+        let mut tapped: u16 = 0;
+
+        for (key, byte) in self.keymap.bindings.iter() {
+            if is_key_down(*key) {
+                down |= 1 << byte;
+            } else if is_key_pressed(*key) {
+                tapped |= 1 << byte;
+            }
+        }
+
+        // Compare against the pending events too, the VM may not have run since the last frame
+        let previous = vm.keypad.latest_mask();
+
+        for byte in 0..16 {
+            let bit = 1 << byte;
+
+            if previous & bit == 0 && (down | tapped) & bit != 0 {
+                vm.key_down(byte);
+            }
+            if (previous | tapped) & bit != 0 && down & bit == 0 {
+                vm.key_up(byte);
+            }
+        }
+    }
+
+    fn update_sound(&mut self, vm: &VM) {
+        if let Some(tone) = self.tone {
+            let sound_on = !self.muted && vm.sound_timer > 0;
+
+            if sound_on && !self.playing {
+                play_sound(tone, PlaySoundParams { looped: true, volume: self.volume });
+                self.playing = true;
+            } else if !sound_on && self.playing {
+                stop_sound(tone);
+                self.playing = false;
+            }
+        }
+    }
+
+    // Run one frame of emulation, through the debugger when there is one
+    // Returns false when the debugger is paused and nothing ran
+    fn run_frame(&mut self, vm: &mut VM, cycles_per_frame: u32) -> bool {
+        match self.debugger.as_mut() {
+            Some(debugger) => {
+                if debugger.paused {
+                    return false;
+                }
+                debugger.run_frame(vm, cycles_per_frame);
+            }
+            None => {
+                if let Err(error) = vm.run_frame(cycles_per_frame) {
+                    self.show_message(error.to_string());
+                }
+            }
+        }
+
+        return true;
+    }
+
+    fn draw_debugger(&self, vm: &VM, debugger: &Debugger, left: f32) {
+        let line_height = 15.0;
+        let font_size = 16.0;
+        let mut lines: Vec<String> = Vec::new();
+
+        lines.push(match (&debugger.stop_reason, debugger.paused) {
+            (Some(reason), true) => reason.clone(),
+            (None, true) => String::from("Paused"),
+            _ => String::from("Running"),
+        });
+        let mnemonic = match decode(vm.get_instruction()) {
+            Ok(instruction) => instruction.to_string(),
+            Err(_) => String::from("???"),
+        };
+        lines.push(format!("PC {:#05X}  {:04X} {}", vm.pc, vm.get_instruction(), mnemonic));
+        lines.push(format!("I  {:#06X}  DT {:02X}  ST {:02X}", vm.i, vm.delay_timer, vm.sound_timer));
+
+        for row in 0..8 {
+            lines.push(format!(
+                "V{:X} {:02X}        V{:X} {:02X}",
+                row, vm.registers[row], row + 8, vm.registers[row + 8]
+            ));
+        }
+
+        let stack: Vec<String> = vm.stack.iter().rev().map(|address| format!("{:03X}", address)).collect();
+        lines.push(format!("Stack {}", stack.join(" ")));
+
+        if let Some(error) = vm.last_error {
+            lines.push(error.to_string());
+        }
+
+        let breakpoints: Vec<String> = debugger
+            .breakpoints
+            .iter()
+            .map(|breakpoint| match breakpoint {
+                Breakpoint::Address(address) => format!("{:03X}", address),
+                Breakpoint::Opcode(pattern) => format!("op {:04X}/{:04X}", pattern.value, pattern.mask),
+            })
+            .collect();
+        lines.push(format!("Break {}", breakpoints.join(" ")));
+        lines.push(String::from("F8 run/pause F10 step F2 break"));
+
+        draw_rectangle(left, 0.0, DEBUG_PANEL_WIDTH, screen_height(), DARKGRAY);
+
+        for (n, line) in lines.iter().enumerate() {
+            draw_text(line, left + 6.0, line_height * (n + 1) as f32, font_size, WHITE);
+        }
+    }
+
+    // The hex keypad with the keyboard keys bound to each key, pressed keys highlighted
+    fn draw_keymap(&self, vm: &VM, display_width: f32) {
+        let cell_width = 64.0;
+        let cell_height = 44.0;
+        let left = (display_width - cell_width * 4.0) / 2.0;
+        let top = (screen_height() - cell_height * 4.0) / 2.0;
+
+        draw_rectangle(left - 4.0, top - 4.0, cell_width * 4.0 + 8.0, cell_height * 4.0 + 8.0, Color::new(0.0, 0.0, 0.0, 0.8));
+
+        for (row, bytes) in KEYPAD.iter().enumerate() {
+            for (column, byte) in bytes.iter().enumerate() {
+                let x = left + column as f32 * cell_width;
+                let y = top + row as f32 * cell_height;
+                let color = if vm.keypad.is_down(*byte) { ORANGE } else { GRAY };
+
+                draw_rectangle_lines(x + 2.0, y + 2.0, cell_width - 4.0, cell_height - 4.0, 2.0, color);
+                draw_text(&format!("{:X}", byte), x + 8.0, y + 20.0, 20.0, WHITE);
+
+                let labels: Vec<String> = self.keymap.keys_for(*byte).map(keymap::key_label).collect();
+                draw_text(&labels.join(" "), x + 8.0, y + 36.0, 16.0, color);
+            }
+        }
+    }
+
+    fn draw(&self, vm: &VM) {
+        clear_background(self.palette[0]);
+
+        let display_width = match self.debugger {
+            Some(_) => screen_width() - DEBUG_PANEL_WIDTH,
+            None => screen_width(),
+        };
+
+        // Scale the current resolution (64x32 or 128x64) to the window
+        let pixel_width = display_width / vm.screen.width() as f32;
+        let pixel_height = screen_height() / vm.screen.height() as f32;
+
+        for (x, col) in vm.screen.pixels.iter().take(vm.screen.width()).enumerate() {
+            for (y, pixel) in col.iter().take(vm.screen.height()).enumerate() {
+                if *pixel != 0 {
+                    draw_rectangle(x as f32 * pixel_width, y as f32 * pixel_height, pixel_width, pixel_height, self.palette[(*pixel & 0b11) as usize]);
+                }
+            }
+        }
+
+        if let Some(debugger) = &self.debugger {
+            self.draw_debugger(vm, debugger, display_width);
+        }
+
+        if self.show_keymap {
+            self.draw_keymap(vm, display_width);
+        }
+
+        if let Some((message, expiry)) = &self.message {
+            if get_time() < *expiry {
+                draw_text(message, 8.0, 20.0, 20.0, YELLOW);
+            }
+        }
+
+        if self.is_paused() {
+            draw_text("Paused", 8.0, screen_height() - 8.0, 20.0, YELLOW);
+        }
+    }
+}
+
+// Each frame runs a fixed number of instructions, ticks the timers once,
+// samples input once and draws once, so emulation follows the display refresh
+async fn launch_frame_locked_frontend(mut vm: VM, mut frontend: Frontend, settings: RunSettings) {
+    loop {
+        if !frontend.handle_hotkeys(&mut vm) {
             break;
         }
 
+        frontend.update_input(&mut vm);
+
+        if frontend.is_rewinding() {
+            frontend.step_back(&mut vm);
+        }
+        else if !frontend.is_paused() && !vm.halted {
+            // Only frames that ran are recorded, so the history isn't filled with
+            // copies of a halted or paused machine
+            if frontend.run_frame(&mut vm, settings.cycles_per_frame) {
+                frontend.record_snapshot(&vm);
+            }
+        }
+
+        frontend.update_sound(&vm);
+        frontend.draw(&vm);
+
+        next_frame().await;
+    }
+}
+
+async fn launch_threaded_frontend(vm_shared: Arc<Mutex<VM>>, mut frontend: Frontend) {
+    loop {
         {
             let mut vm = vm_shared.lock().unwrap();
 
-            vm.keys_pressed.clear();
-
-            for (key, byte) in KEYMAP.iter() {
-                if is_key_down(*key) {
-                    vm.keys_pressed.push(*byte);
-                }
+            if !frontend.handle_hotkeys(&mut vm) {
+                // The VM thread still holds the machine, so the trace is closed here
+                vm.tracer = None;
+                break;
             }
 
+            frontend.update_input(&mut vm);
+            frontend.update_sound(&vm);
         }
 
         {
             let vm = vm_shared.lock().unwrap();
 
-            for (x, col) in vm.screen.pixels.iter().enumerate() {
-                for (y, pixel) in col.iter().enumerate() {
-                    if *pixel {
-                        draw_rectangle((x * 8) as f32, (y * 8) as f32, 8f32, 8f32, WHITE);
-                    }
-                }
-            }
+            frontend.draw(&vm);
         }
 
         next_frame().await;
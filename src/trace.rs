@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::debugger::{parse_address, OpcodePattern};
+use crate::instruction::decode;
+
+pub static TRACE_FORMAT_NAMES: &[&str] = &["text", "binary"];
+
+// Start of binary traces, followed by a version byte
+static TRACE_MAGIC: &[u8; 4] = b"C8TR";
+static TRACE_VERSION: u8 = 1;
+
+static TRACE_WRITE_ERROR: &str = "Could not write trace";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    // One line per instruction
+    #[default]
+    Text,
+    // Per instruction: cycle (u64), PC, opcode and I (u16), all little endian, then the
+    // number of changed registers and a (register, old value, new value) triple for each
+    Binary,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name.to_lowercase().as_str() {
+            "text" => Some(TraceFormat::Text),
+            "binary" | "bin" => Some(TraceFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+// Addresses from `start` to `end`, both included
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PcRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PcRange {
+    // Parse "0x200-0x2FF", or a single address
+    pub fn parse(text: &str) -> Option<PcRange> {
+        let (start, end) = text.split_once('-').unwrap_or((text, text));
+        let range = PcRange { start: parse_address(start)?, end: parse_address(end)? };
+
+        if range.start > range.end {
+            return None;
+        }
+
+        return Some(range);
+    }
+
+    pub fn contains(&self, pc: u16) -> bool {
+        (self.start..=self.end).contains(&pc)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceSettings {
+    pub format: TraceFormat,
+    pub pc_range: Option<PcRange>,
+    // Instructions matching any of the patterns are traced, every instruction when empty
+    pub opcodes: Vec<OpcodePattern>,
+    // Only keep the last instructions, written when the VM halts or the trace ends
+    pub last: Option<usize>,
+}
+
+// An executed instruction, with the registers it changed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    // I after the instruction
+    pub i: u16,
+    // (register, old value, new value)
+    pub changes: Vec<(u8, u8, u8)>,
+}
+
+impl TraceEntry {
+    pub fn new(cycle: u64, pc: u16, opcode: u16, i: u16, before: &[u8; 16], after: &[u8; 16]) -> TraceEntry {
+        let changes = (0..16u8)
+            .filter(|register| before[*register as usize] != after[*register as usize])
+            .map(|register| (register, before[register as usize], after[register as usize]))
+            .collect();
+
+        TraceEntry { cycle, pc, opcode, i, changes }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mnemonic = match decode(self.opcode) {
+            Ok(instruction) => instruction.to_string(),
+            Err(_) => String::from("???"),
+        };
+
+        let mut line = format!("{:>8}  {:#05X}  {:04X}  {:<20} I={:04X}", self.cycle, self.pc, self.opcode, mnemonic, self.i);
+        for (register, old, new) in self.changes.iter() {
+            line.push_str(&format!(" V{:X}={:02X}->{:02X}", register, old, new));
+        }
+
+        return line;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(15 + 3 * self.changes.len());
+
+        bytes.extend_from_slice(&self.cycle.to_le_bytes());
+        bytes.extend_from_slice(&self.pc.to_le_bytes());
+        bytes.extend_from_slice(&self.opcode.to_le_bytes());
+        bytes.extend_from_slice(&self.i.to_le_bytes());
+        bytes.push(self.changes.len() as u8);
+        for (register, old, new) in self.changes.iter() {
+            bytes.extend_from_slice(&[*register, *old, *new]);
+        }
+
+        return bytes;
+    }
+}
+
+// Logs the instructions executed by a VM
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    format: TraceFormat,
+    pc_range: Option<PcRange>,
+    opcodes: Vec<OpcodePattern>,
+    last: Option<usize>,
+    recent: VecDeque<TraceEntry>,
+    // Tracing stops after the first write error
+    failed: bool,
+}
+
+impl Tracer {
+    pub fn new(mut writer: Box<dyn Write + Send>, settings: TraceSettings) -> io::Result<Tracer> {
+        if settings.format == TraceFormat::Binary {
+            writer.write_all(TRACE_MAGIC)?;
+            writer.write_all(&[TRACE_VERSION])?;
+        }
+
+        return Ok(Tracer {
+            writer,
+            format: settings.format,
+            pc_range: settings.pc_range,
+            opcodes: settings.opcodes,
+            last: settings.last,
+            recent: VecDeque::new(),
+            failed: false,
+        });
+    }
+
+    pub fn create(path: &Path, settings: TraceSettings) -> io::Result<Tracer> {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+
+        return Tracer::new(Box::new(file), settings);
+    }
+
+    // Whether the instruction at `pc` passes the filters
+    pub fn traces(&self, pc: u16, opcode: u16) -> bool {
+        if self.failed || self.last == Some(0) {
+            return false;
+        }
+
+        let in_range = self.pc_range.is_none_or(|range| range.contains(pc));
+        let matches = self.opcodes.is_empty() || self.opcodes.iter().any(|pattern| pattern.matches(opcode));
+
+        return in_range && matches;
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        match self.last {
+            Some(last) => {
+                if self.recent.len() >= last {
+                    self.recent.pop_front();
+                }
+                self.recent.push_back(entry);
+            }
+            None => self.write(&entry),
+        }
+    }
+
+    // Write the instructions kept so far, called when the VM halts and when dropped
+    pub fn finish(&mut self) {
+        while let Some(entry) = self.recent.pop_front() {
+            self.write(&entry);
+        }
+
+        self.flush();
+    }
+
+    fn write(&mut self, entry: &TraceEntry) {
+        if self.failed {
+            return;
+        }
+
+        let result = match self.format {
+            TraceFormat::Text => writeln!(self.writer, "{}", entry.to_text()),
+            TraceFormat::Binary => self.writer.write_all(&entry.to_bytes()),
+        };
+
+        if let Err(error) = result {
+            self.fail(error);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.failed {
+            return;
+        }
+
+        if let Err(error) = self.writer.flush() {
+            self.fail(error);
+        }
+    }
+
+    // The VM keeps running without a trace rather than stopping
+    fn fail(&mut self, error: io::Error) {
+        eprintln!("{}: {}", TRACE_WRITE_ERROR, error);
+        self.failed = true;
+    }
+}
+
+// Runs can also end without a halt, e.g. when the window is closed or a cycle limit
+// is reached, and the instructions kept for --trace-last are written then
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,316 @@
+use std::io;
+
+use console_engine::pixel;
+use console_engine::{Color, ConsoleEngine, KeyCode, KeyModifiers};
+use macroquad::prelude::KeyCode as WindowKeyCode;
+
+use crate::chip8::VM;
+use crate::instruction::decode;
+use crate::runner::RunSettings;
+
+pub static GLYPH_NAMES: &[&str] = &["half-block", "braille"];
+
+// Terminals only report key presses and their auto-repeats, so a key counts as
+// down for a few frames after its last event
+static KEY_HOLD_FRAMES: usize = 8;
+
+static FRAME_RATE: u32 = 60;
+static PANEL_WIDTH: u32 = 24;
+static PANEL_HEIGHT: u32 = 18;
+
+static PAUSE_KEY: KeyCode = KeyCode::F(3);
+static RESET_KEY: KeyCode = KeyCode::F(4);
+
+// How CHIP-8 pixels are packed into terminal cells
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Glyphs {
+    // 1x2 pixels per cell, with the top pixel as foreground and the bottom one as background
+    HalfBlock,
+    // 2x4 pixels per cell, in a single color
+    Braille,
+}
+
+impl Glyphs {
+    pub fn from_name(name: &str) -> Option<Glyphs> {
+        match name.to_lowercase().as_str() {
+            "half-block" | "half" => Some(Glyphs::HalfBlock),
+            "braille" => Some(Glyphs::Braille),
+            _ => None,
+        }
+    }
+
+    // Pixels covered by one cell, horizontally then vertically
+    fn cell_size(&self) -> (usize, usize) {
+        match self {
+            Glyphs::HalfBlock => (1, 2),
+            Glyphs::Braille => (2, 4),
+        }
+    }
+}
+
+// Terminal key for a window key of the keymap, the keypad digits can't be told apart
+fn terminal_key(key: WindowKeyCode) -> Option<KeyCode> {
+    let name = format!("{:?}", key);
+
+    let character = match key {
+        WindowKeyCode::Up => return Some(KeyCode::Up),
+        WindowKeyCode::Down => return Some(KeyCode::Down),
+        WindowKeyCode::Left => return Some(KeyCode::Left),
+        WindowKeyCode::Right => return Some(KeyCode::Right),
+        WindowKeyCode::Enter => return Some(KeyCode::Enter),
+        WindowKeyCode::Tab => return Some(KeyCode::Tab),
+        WindowKeyCode::Space => ' ',
+        WindowKeyCode::Comma => ',',
+        WindowKeyCode::Period => '.',
+        WindowKeyCode::Semicolon => ';',
+        WindowKeyCode::Slash => '/',
+        WindowKeyCode::Minus => '-',
+        WindowKeyCode::Equal => '=',
+        _ => {
+            // Letters are named A to Z, digits Key0 to Key9 and Kp0 to Kp9
+            let last = name.chars().last()?;
+            if name.len() == 1 || name.starts_with("Key") || name.starts_with("Kp") {
+                last.to_ascii_lowercase()
+            } else {
+                return None;
+            }
+        }
+    };
+
+    return Some(KeyCode::Char(character));
+}
+
+fn terminal_color(color: macroquad::color::Color) -> Color {
+    Color::Rgb {
+        r: (color.r * 255.0) as u8,
+        g: (color.g * 255.0) as u8,
+        b: (color.b * 255.0) as u8,
+    }
+}
+
+struct Terminal {
+    engine: ConsoleEngine,
+    glyphs: Glyphs,
+    palette: [Color; 4],
+    // Terminal keys with the CHIP-8 key they are bound to
+    bindings: Vec<(KeyCode, u8)>,
+    // Frame of the last event of each CHIP-8 key
+    last_seen: [Option<usize>; 16],
+    initial_state: Vec<u8>,
+    paused: bool,
+    message: Option<String>,
+}
+
+impl Terminal {
+    // Keys held, as a mask with bit K set for key K
+    fn key_mask(&mut self) -> u16 {
+        let frame = self.engine.frame_count;
+        let mut mask: u16 = 0;
+
+        for (key, byte) in self.bindings.iter() {
+            if self.engine.is_key_pressed(*key) || self.engine.is_key_held(*key) {
+                self.last_seen[*byte as usize] = Some(frame);
+            }
+        }
+
+        for (byte, last_seen) in self.last_seen.iter().enumerate() {
+            if let Some(seen) = last_seen {
+                if frame.wrapping_sub(*seen) < KEY_HOLD_FRAMES {
+                    mask |= 1 << byte;
+                }
+            }
+        }
+
+        return mask;
+    }
+
+    fn update_input(&mut self, vm: &mut VM) {
+        let down = self.key_mask();
+        let previous = vm.keypad.latest_mask();
+
+        for byte in 0..16 {
+            let bit = 1 << byte;
+
+            if previous & bit == 0 && down & bit != 0 {
+                vm.key_down(byte);
+            } else if previous & bit != 0 && down & bit == 0 {
+                vm.key_up(byte);
+            }
+        }
+    }
+
+    // Returns false when the user wants to quit
+    fn handle_hotkeys(&mut self, vm: &mut VM) -> bool {
+        if self.engine.is_key_pressed(KeyCode::Esc)
+            || self.engine.is_key_pressed_with_modifier(KeyCode::Char('c'), KeyModifiers::CONTROL)
+        {
+            return false;
+        }
+
+        if self.engine.is_key_pressed(PAUSE_KEY) {
+            self.paused = !self.paused;
+        }
+
+        if self.engine.is_key_pressed(RESET_KEY) {
+            match vm.load_state(&self.initial_state) {
+                Ok(()) => self.message = Some(String::from("Reset")),
+                Err(error) => self.message = Some(error.to_string()),
+            }
+        }
+
+        return true;
+    }
+
+    // Lit pixels are merged two by two when the display doesn't fit the terminal
+    fn pixel(&self, vm: &VM, scale: usize, x: usize, y: usize) -> u8 {
+        let mut value = 0;
+
+        for dx in 0..scale {
+            for dy in 0..scale {
+                let (px, py) = (x * scale + dx, y * scale + dy);
+                if px < vm.screen.width() && py < vm.screen.height() {
+                    value = value.max(vm.screen.pixels[px][py] & 0b11);
+                }
+            }
+        }
+
+        return value;
+    }
+
+    // Returns the number of columns drawn
+    fn draw_display(&mut self, vm: &VM) -> usize {
+        let (cell_width, cell_height) = self.glyphs.cell_size();
+        let available = (self.engine.get_width().saturating_sub(PANEL_WIDTH)) as usize;
+        let scale = if vm.screen.width() / cell_width > available { 2 } else { 1 };
+
+        let columns = vm.screen.width() / scale / cell_width;
+        let rows = vm.screen.height() / scale / cell_height;
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let (x, y) = (column * cell_width, row * cell_height);
+
+                let cell = match self.glyphs {
+                    Glyphs::HalfBlock => {
+                        let top = self.palette[self.pixel(vm, scale, x, y) as usize];
+                        let bottom = self.palette[self.pixel(vm, scale, x, y + 1) as usize];
+                        pixel::pxl_fbg('▀', top, bottom)
+                    }
+                    Glyphs::Braille => {
+                        // Dot bits of a braille cell, by column then row
+                        let dots = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+                        let mut bits: u32 = 0;
+                        let mut value = 0;
+
+                        for (dx, column_dots) in dots.iter().enumerate() {
+                            for (dy, dot) in column_dots.iter().enumerate() {
+                                let pixel = self.pixel(vm, scale, x + dx, y + dy);
+                                if pixel != 0 {
+                                    bits |= dot;
+                                    value = value.max(pixel);
+                                }
+                            }
+                        }
+
+                        let character = char::from_u32(0x2800 + bits).unwrap_or(' ');
+                        pixel::pxl_fbg(character, self.palette[value as usize], self.palette[0])
+                    }
+                };
+
+                self.engine.set_pxl(column as i32, row as i32, cell);
+            }
+        }
+
+        return columns;
+    }
+
+    fn draw_panel(&mut self, vm: &VM, left: i32) {
+        let mut lines: Vec<String> = Vec::new();
+
+        let status = if vm.halted {
+            String::from("Halted")
+        } else if self.paused {
+            String::from("Paused")
+        } else {
+            String::from("Running")
+        };
+        lines.push(status);
+
+        let mnemonic = match decode(vm.get_instruction()) {
+            Ok(instruction) => instruction.to_string(),
+            Err(_) => String::from("???"),
+        };
+        lines.push(format!("PC {:03X} {:04X}", vm.pc, vm.get_instruction()));
+        lines.push(format!("   {}", mnemonic));
+        lines.push(format!("I  {:04X}", vm.i));
+        lines.push(format!("DT {:02X}  ST {:02X}", vm.delay_timer, vm.sound_timer));
+
+        for row in 0..8 {
+            lines.push(format!("V{:X} {:02X}  V{:X} {:02X}", row, vm.registers[row], row + 8, vm.registers[row + 8]));
+        }
+
+        let keys: Vec<String> = vm.keypad.keys().map(|key| format!("{:X}", key)).collect();
+        lines.push(format!("Keys {}", keys.join(" ")));
+
+        if let Some(message) = &self.message {
+            lines.push(message.clone());
+        }
+
+        lines.push(String::from("Esc quit F3 pause"));
+        lines.push(String::from("F4 reset"));
+
+        for (n, line) in lines.iter().enumerate() {
+            self.engine.print(left, n as i32, line);
+        }
+    }
+}
+
+// Run the VM in the terminal, frame-locked at 60 frames per second
+pub fn run(mut vm: VM, settings: &RunSettings, glyphs: Glyphs) -> io::Result<()> {
+    let (cell_width, cell_height) = glyphs.cell_size();
+    // Enough room for the low resolution display, the high resolution one is scaled down if needed
+    let width = (64 / cell_width) as u32 + PANEL_WIDTH;
+    let height = ((32 / cell_height) as u32).max(PANEL_HEIGHT);
+
+    let bindings = settings
+        .keymap
+        .bindings
+        .iter()
+        .filter_map(|(key, byte)| terminal_key(*key).map(|key| (key, *byte)))
+        .collect();
+
+    let mut terminal = Terminal {
+        engine: ConsoleEngine::init_fill_require(width, height, FRAME_RATE)?,
+        glyphs,
+        palette: settings.palette.map(terminal_color),
+        bindings,
+        last_seen: [None; 16],
+        initial_state: vm.save_state(),
+        paused: false,
+        message: None,
+    };
+
+    loop {
+        terminal.engine.wait_frame();
+        terminal.engine.check_resize();
+
+        if !terminal.handle_hotkeys(&mut vm) {
+            break;
+        }
+
+        terminal.update_input(&mut vm);
+
+        if !terminal.paused && !vm.halted {
+            if let Err(error) = vm.run_frame(settings.cycles_per_frame) {
+                terminal.message = Some(error.to_string());
+            }
+        }
+
+        terminal.engine.clear_screen();
+        let columns = terminal.draw_display(&vm);
+        terminal.draw_panel(&vm, columns as i32 + 2);
+        terminal.engine.draw();
+    }
+
+    return Ok(());
+}
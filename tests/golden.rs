@@ -0,0 +1,429 @@
+// Golden-screen tests: each ROM in tests/roms runs headlessly for a fixed number of
+// cycles and its final screen is compared with the ASCII art in tests/golden.
+// Octo sources (.8o) are assembled first, other files are loaded like the emulator does,
+// so binary test ROMs such as the Timendus suite can be dropped in with a new case.
+//
+// Every screen is also checked against reference values that don't come from the
+// emulator: the results listed in the ROM comments, read back from the screen with
+// the font of Cowgod's CHIP-8 technical reference.
+//
+// Run with CHIPR_BLESS=1 to write the golden files from the current output. Screens
+// failing their reference check are never written.
+//
+// The Timendus chip8-test-suite binaries go in tests/roms/timendus under their
+// upstream names. Their tests are ignored until the files are there: run them with
+// `cargo test --test golden -- --ignored`, bless the screens once every result the
+// suite draws is a pass, then drop the #[ignore].
+
+#![allow(clippy::needless_return)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chipr::asm;
+use chipr::chip8::{TimerMode, VM};
+use chipr::headless::ascii_rows;
+use chipr::quirks::Quirks;
+use chipr::reader::{self, LoadOptions, DEFAULT_LOAD_ADDRESS};
+
+struct Case {
+    rom: &'static str,
+    quirks: &'static str,
+    cycles: u64,
+    // Key changes as (cycle, key, pressed)
+    keys: &'static [(u64, u8, bool)],
+    // Written to 0x1FF before starting, where the Timendus ROMs look for the
+    // platform or test to run instead of showing their menu
+    selection: Option<u8>,
+}
+
+impl Case {
+    fn new(rom: &'static str, quirks: &'static str) -> Case {
+        Case { rom, quirks, cycles: 10_000, keys: &[], selection: None }
+    }
+
+    fn timendus(rom: &'static str, quirks: &'static str, selection: Option<u8>) -> Case {
+        Case { cycles: 100_000, selection, ..Case::new(rom, quirks) }
+    }
+}
+
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+fn read_program(path: &Path) -> Vec<u8> {
+    if !path.exists() {
+        panic!("{} is not in the tree, see the top of tests/golden.rs", path.display());
+    }
+
+    if path.extension().is_some_and(|extension| extension == "8o") {
+        return asm::assemble_file(path).unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    return reader::read_rom_bytes(&path.to_string_lossy()).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+}
+
+fn run(case: &Case) -> Vec<String> {
+    let quirks = Quirks::from_name(case.quirks).expect("unknown quirks preset");
+    let program = read_program(&root().join("roms").join(case.rom));
+    let options = LoadOptions { load_address: DEFAULT_LOAD_ADDRESS, memory_size: quirks.memory_size };
+
+    let mut vm = VM::new();
+    vm.set_quirks(quirks);
+    vm.set_timer_mode(TimerMode::Instructions);
+    vm.load_rom(reader::load_program(&program, options).unwrap(), DEFAULT_LOAD_ADDRESS);
+    vm.init_font();
+    if let Some(selection) = case.selection {
+        vm.memory[0x1FF] = selection;
+    }
+
+    let mut keys = case.keys.iter().peekable();
+
+    while vm.cycles < case.cycles && !vm.halted {
+        while let Some((_, key, pressed)) = keys.next_if(|(cycle, _, _)| *cycle <= vm.cycles) {
+            if *pressed {
+                vm.key_down(*key);
+            } else {
+                vm.key_up(*key);
+            }
+        }
+
+        if let Err(error) = vm.next() {
+            panic!("{} stopped at cycle {}: {}", case.rom, vm.cycles, error);
+        }
+    }
+
+    return ascii_rows(&vm.screen);
+}
+
+// The standard 4x5 hex font, typed from the reference rather than read from the VM
+static HEX_GLYPHS: [[&str; 5]; 16] = [
+    ["####", "#..#", "#..#", "#..#", "####"],
+    ["..#.", ".##.", "..#.", "..#.", ".###"],
+    ["####", "...#", "####", "#...", "####"],
+    ["####", "...#", "####", "...#", "####"],
+    ["#..#", "#..#", "####", "...#", "...#"],
+    ["####", "#...", "####", "...#", "####"],
+    ["####", "#...", "####", "#..#", "####"],
+    ["####", "...#", "..#.", ".#..", ".#.."],
+    ["####", "#..#", "####", "#..#", "####"],
+    ["####", "#..#", "####", "...#", "####"],
+    ["####", "#..#", "####", "#..#", "#..#"],
+    ["###.", "#..#", "###.", "#..#", "###."],
+    ["####", "#...", "#...", "#...", "####"],
+    ["###.", "#..#", "#..#", "#..#", "###."],
+    ["####", "#...", "####", "#...", "####"],
+    ["####", "#...", "####", "#...", "#..."],
+];
+
+fn cell(rows: &[String], x: usize, y: usize, width: usize, height: usize) -> Vec<String> {
+    rows[y..y + height].iter().map(|row| row[x..x + width].to_string()).collect()
+}
+
+// The hex digit drawn with its top left corner at (x, y), '?' if there is none
+fn hex_digit_at(rows: &[String], x: usize, y: usize) -> char {
+    let drawn = cell(rows, x, y, 4, 5);
+
+    return HEX_GLYPHS
+        .iter()
+        .position(|glyph| drawn == glyph)
+        .and_then(|digit| std::char::from_digit(digit as u32, 16))
+        .map_or('?', |digit| digit.to_ascii_uppercase());
+}
+
+fn hex_digits_at(rows: &[String], positions: &[(usize, usize)]) -> String {
+    positions.iter().map(|(x, y)| hex_digit_at(rows, *x, *y)).collect()
+}
+
+// Whether the sprite `bytes` is drawn at (x, y) on an otherwise blank area
+fn sprite_at(rows: &[String], x: usize, y: usize, bytes: &[u8]) -> bool {
+    let expected: Vec<String> = bytes
+        .iter()
+        .map(|byte| (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect())
+        .collect();
+
+    return cell(rows, x, y, 8, bytes.len()) == expected;
+}
+
+// Bytes following `: label` in an Octo source, such as the sprites of a test ROM
+fn source_bytes(rom: &str, label: &str) -> Vec<u8> {
+    let source = fs::read_to_string(root().join("roms").join(rom)).unwrap();
+    let line = source
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!(": {} ", label)))
+        .unwrap_or_else(|| panic!("{} has no label {}", rom, label));
+
+    return line
+        .split_whitespace()
+        .map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16).unwrap())
+        .collect();
+}
+
+// Expected values written as ": XX YY" at the end of the comments of an Octo source
+fn commented_values(rom: &str) -> Vec<String> {
+    let source = fs::read_to_string(root().join("roms").join(rom)).unwrap();
+
+    return source
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix('#')?.rsplit_once(": "))
+        .map(|(_, values)| values.to_string())
+        .filter(|values| values.len() == 5 && values.chars().all(|c| c.is_ascii_hexdigit() || c == ' '))
+        .collect();
+}
+
+fn expect_equal<T: PartialEq + std::fmt::Debug>(what: &str, found: T, expected: T) -> Result<(), String> {
+    if found != expected {
+        return Err(format!("{}: found {:?}, expected {:?}", what, found, expected));
+    }
+
+    return Ok(());
+}
+
+fn check(name: &str, case: Case, reference: &dyn Fn(&[String]) -> Result<(), String>) {
+    let golden_path = root().join("golden").join(format!("{}.txt", name));
+    let rows = run(&case);
+    let screen = to_text(&rows);
+
+    if let Err(error) = reference(&rows) {
+        panic!("screen of {} fails its reference check, {}:\n{}", name, error, screen);
+    }
+
+    if env::var_os("CHIPR_BLESS").is_some() {
+        fs::write(&golden_path, &screen).unwrap();
+        return;
+    }
+
+    let golden = fs::read_to_string(&golden_path)
+        .unwrap_or_else(|error| panic!("{}: {}, run with CHIPR_BLESS=1 to create it", golden_path.display(), error));
+
+    if screen != golden {
+        panic!("screen of {} differs from {}:\n{}", name, golden_path.display(), screen);
+    }
+}
+
+fn to_text(rows: &[String]) -> String {
+    let mut text = rows.join("\n");
+    text.push('\n');
+
+    return text;
+}
+
+// Letters from the sprites of logo.8o, then the 16 font digits on two rows
+fn logo_reference(rows: &[String]) -> Result<(), String> {
+    for (index, letter) in ["letter-c", "letter-h", "letter-i", "letter-p", "letter-r"].iter().enumerate() {
+        if !sprite_at(rows, 12 + 8 * index, 2, &source_bytes("logo.8o", letter)) {
+            return Err(format!("{} is not drawn at ({}, 2)", letter, 12 + 8 * index));
+        }
+    }
+
+    let positions: Vec<(usize, usize)> = (0..16).map(|digit| (8 * (digit % 8), 14 + 7 * (digit / 8))).collect();
+
+    return expect_equal("font digits", hex_digits_at(rows, &positions), String::from("0123456789ABCDEF"));
+}
+
+// One "RR FF" pair per comment of flags.8o, two per row
+fn flags_reference(rows: &[String]) -> Result<(), String> {
+    let expected = commented_values("flags.8o");
+    if expected.len() != 9 {
+        return Err(format!("expected 9 commented results in flags.8o, found {}", expected.len()));
+    }
+
+    let found: Vec<String> = (0..expected.len())
+        .map(|slot| {
+            let (x, y) = (32 * (slot % 2), 6 * (slot / 2));
+            let digits = hex_digits_at(rows, &[(x, y), (x + 5, y), (x + 12, y), (x + 17, y)]);
+            format!("{} {}", &digits[..2], &digits[2..])
+        })
+        .collect();
+
+    return expect_equal("results", found, expected);
+}
+
+// Digits for the shift, load/store, jump and VF reset quirks as described in quirks.8o,
+// then whether the block drawn at x = 60 wraps around to the left edge
+fn quirks_reference(digits: &'static str, wraps: bool) -> impl Fn(&[String]) -> Result<(), String> {
+    move |rows: &[String]| {
+        let positions = [(0, 0), (6, 0), (12, 0), (18, 0)];
+        expect_equal("quirk digits", hex_digits_at(rows, &positions).as_str(), digits)?;
+
+        let block = vec![String::from("####"); 4];
+        expect_equal("block at the right edge", cell(rows, 60, 16, 4, 4), block.clone())?;
+
+        let wrapped = cell(rows, 0, 16, 4, 4) == block;
+        return expect_equal("block wraps", wrapped, wraps);
+    }
+}
+
+// The keys given to FX0A and the held E, drawn left to right
+fn keypad_reference(rows: &[String]) -> Result<(), String> {
+    let positions = [(0, 0), (6, 0), (12, 0), (18, 0)];
+
+    return expect_equal("keys", hex_digits_at(rows, &positions), String::from("15AE"));
+}
+
+// The six 15-row sprites stored after the code of the IBM logo ROM, drawn at the
+// positions its program uses and with nothing else on the screen
+fn ibm_logo_reference(rows: &[String]) -> Result<(), String> {
+    let rom = fs::read(root().join("roms").join("ibm-logo.ch8")).unwrap();
+    let sprites = rom[0x2A..].chunks(15);
+    let positions = [12, 21, 29, 33, 41, 49];
+
+    let mut expected = vec![vec!['.'; 64]; 32];
+    for (x, sprite) in positions.iter().zip(sprites) {
+        for (row, byte) in sprite.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    expected[8 + row][x + bit] = '#';
+                }
+            }
+        }
+    }
+    let expected: Vec<String> = expected.into_iter().map(|row| row.into_iter().collect()).collect();
+
+    return expect_equal("logo", rows, &expected[..]);
+}
+
+// The Timendus ROMs draw a pass or fail mark for each of their tests, which is what
+// their goldens have to be reviewed for
+fn reviewed_screen(_: &[String]) -> Result<(), String> {
+    return Ok(());
+}
+
+// Big digits 0 to 9 moved by 4 pixels down then 4 pixels right, then the line
+// drawn after scrolling. The 8x10 font has no common standard, so its glyphs are
+// taken from the VM.
+fn hires_reference(rows: &[String]) -> Result<(), String> {
+    let mut vm = VM::new();
+    vm.init_font();
+
+    for digit in 0..10 {
+        let glyph = &vm.memory[0xA0 + 10 * digit..0xA0 + 10 * digit + 10];
+        if !sprite_at(rows, 4 + 12 * digit, 4, glyph) {
+            return Err(format!("big digit {} is not drawn at ({}, 4)", digit, 4 + 12 * digit));
+        }
+    }
+
+    if !sprite_at(rows, 0, 40, &[0xFF]) {
+        return Err(String::from("the line is not drawn at (0, 40)"));
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn logo() {
+    check("logo", Case::new("logo.8o", "chipr"), &logo_reference);
+}
+
+#[test]
+fn flags() {
+    check("flags", Case::new("flags.8o", "chipr"), &flags_reference);
+}
+
+#[test]
+fn flags_cosmac_vip() {
+    check("flags-vip", Case::new("flags.8o", "cosmac-vip"), &flags_reference);
+}
+
+#[test]
+fn quirks_chipr() {
+    // The historical chipr behavior: VX shifted in place, I unchanged, BNNN adding V0,
+    // VF kept and sprites wrapping
+    check("quirks-chipr", Case::new("quirks.8o", "chipr"), &quirks_reference("0A15", true));
+}
+
+#[test]
+fn quirks_cosmac_vip() {
+    check("quirks-vip", Case::new("quirks.8o", "cosmac-vip"), &quirks_reference("2B10", false));
+}
+
+#[test]
+fn quirks_schip() {
+    check("quirks-schip", Case::new("quirks.8o", "schip-legacy"), &quirks_reference("0A25", false));
+}
+
+#[test]
+fn keypad() {
+    let keys = &[(100, 0x1, true), (150, 0x1, false), (300, 0x5, true), (350, 0x5, false), (500, 0xA, true), (520, 0xA, false), (700, 0xE, true)];
+
+    check("keypad", Case { keys, ..Case::new("keypad.8o", "chipr") }, &keypad_reference);
+}
+
+#[test]
+fn keypad_release() {
+    // The key is only taken on release, so holding 5 past the next FX0A doesn't repeat it
+    let keys = &[(100, 0x1, true), (150, 0x1, false), (300, 0x5, true), (2000, 0x5, false), (2500, 0xA, true), (2520, 0xA, false), (2700, 0xE, true)];
+
+    check("keypad", Case { keys, ..Case::new("keypad.8o", "cosmac-vip") }, &keypad_reference);
+}
+
+#[test]
+fn hires() {
+    check("hires", Case::new("hires.8o", "schip-modern"), &hires_reference);
+}
+
+#[test]
+fn ibm_logo() {
+    check("ibm-logo", Case::new("ibm-logo.ch8", "cosmac-vip"), &ibm_logo_reference);
+}
+
+#[test]
+#[ignore = "tests/roms/timendus/1-chip8-logo.ch8 is not vendored yet"]
+fn timendus_chip8_logo() {
+    check("timendus-chip8-logo", Case::timendus("timendus/1-chip8-logo.ch8", "cosmac-vip", None), &reviewed_screen);
+}
+
+#[test]
+#[ignore = "tests/roms/timendus/2-ibm-logo.ch8 is not vendored yet"]
+fn timendus_ibm_logo() {
+    check("timendus-ibm-logo", Case::timendus("timendus/2-ibm-logo.ch8", "cosmac-vip", None), &reviewed_screen);
+}
+
+#[test]
+#[ignore = "tests/roms/timendus/3-corax+.ch8 is not vendored yet"]
+fn timendus_corax_plus() {
+    check("timendus-corax-plus", Case::timendus("timendus/3-corax+.ch8", "cosmac-vip", None), &reviewed_screen);
+}
+
+#[test]
+#[ignore = "tests/roms/timendus/4-flags.ch8 is not vendored yet"]
+fn timendus_flags() {
+    check("timendus-flags", Case::timendus("timendus/4-flags.ch8", "cosmac-vip", None), &reviewed_screen);
+}
+
+// The quirks ROM runs the checks of the platform selected at 0x1FF:
+// 1 CHIP-8, 2 SUPER-CHIP modern, 3 XO-CHIP, 4 SUPER-CHIP legacy
+#[test]
+#[ignore = "tests/roms/timendus/5-quirks.ch8 is not vendored yet"]
+fn timendus_quirks_cosmac_vip() {
+    check("timendus-quirks-vip", Case::timendus("timendus/5-quirks.ch8", "cosmac-vip", Some(1)), &reviewed_screen);
+}
+
+#[test]
+#[ignore = "tests/roms/timendus/5-quirks.ch8 is not vendored yet"]
+fn timendus_quirks_schip_modern() {
+    check("timendus-quirks-schip-modern", Case::timendus("timendus/5-quirks.ch8", "schip-modern", Some(2)), &reviewed_screen);
+}
+
+#[test]
+#[ignore = "tests/roms/timendus/5-quirks.ch8 is not vendored yet"]
+fn timendus_quirks_xo_chip() {
+    check("timendus-quirks-xo-chip", Case::timendus("timendus/5-quirks.ch8", "xo-chip", Some(3)), &reviewed_screen);
+}
+
+#[test]
+#[ignore = "tests/roms/timendus/5-quirks.ch8 is not vendored yet"]
+fn timendus_quirks_schip_legacy() {
+    check("timendus-quirks-schip-legacy", Case::timendus("timendus/5-quirks.ch8", "schip-legacy", Some(4)), &reviewed_screen);
+}
+
+// Test 3 is FX0A, which waits for the key pressed and released here
+#[test]
+#[ignore = "tests/roms/timendus/6-keypad.ch8 is not vendored yet"]
+fn timendus_keypad() {
+    let keys = &[(1000, 0x5, true), (1100, 0x5, false)];
+    let case = Case { keys, ..Case::timendus("timendus/6-keypad.ch8", "cosmac-vip", Some(3)) };
+
+    check("timendus-keypad", case, &reviewed_screen);
+}